@@ -0,0 +1,948 @@
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+pub const CPU_CLOCK: u32 = 4194304;
+const SAMPLE_CYCLES: u32 = 16; // machine cycles averaged into each output sample
+pub const SAMPLE_RATE: u32 = CPU_CLOCK / 4 / SAMPLE_CYCLES;
+const MAX_BUFFERED_SAMPLES: usize = SAMPLE_RATE as usize * 2; // one second of stereo frames
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// Bits that always read back as 1 for 0xFF10 - 0xFF26, write only bits included
+const READ_MASKS: [u8; 0x17] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10 - NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // unused, NR21 - NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30 - NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // unused, NR41 - NR44
+    0x00, 0x00, 0x70, // NR50 - NR52
+];
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct LengthCounter {
+    pub counter: u16,
+    pub enabled: bool,
+}
+
+impl LengthCounter {
+    // Returns true when the counter runs out and the channel has to be turned off
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Envelope {
+    pub initial_volume: u8,
+    pub increase: bool,
+    pub period: u8,
+    pub volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, value: u8) {
+        self.initial_volume = value >> 4;
+        self.increase = value & 0x08 != 0;
+        self.period = value & 0x07;
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Sweep {
+    pub period: u8,
+    pub negate: bool,
+    pub shift: u8,
+    enabled: bool,
+    shadow_frequency: u16,
+    timer: u8,
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct PulseChannel {
+    pub enabled: bool,
+    pub dac_enabled: bool,
+    pub duty: u8,
+    pub frequency: u16,
+    pub length: LengthCounter,
+    pub envelope: Envelope,
+    pub sweep: Option<Sweep>, // only channel 1 has one
+    duty_position: u8,
+    timer: u16,
+}
+
+impl PulseChannel {
+    fn new(has_sweep: bool) -> PulseChannel {
+        PulseChannel {
+            sweep: if has_sweep { Some(Sweep::default()) } else { None },
+            ..Default::default()
+        }
+    }
+
+    fn write(&mut self, reg: usize, value: u8) {
+        match reg {
+            0 => {
+                if let Some(sweep) = self.sweep.as_mut() {
+                    sweep.period = (value >> 4) & 0x07;
+                    sweep.negate = value & 0x08 != 0;
+                    sweep.shift = value & 0x07;
+                }
+            }
+            1 => {
+                self.duty = value >> 6;
+                self.length.counter = 64 - (value & 0x3F) as u16;
+            }
+            2 => {
+                self.envelope.write(value);
+                self.dac_enabled = value & 0xF8 != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | (((value & 0x07) as u16) << 8);
+                self.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => panic!("invalid pulse register: {}", reg),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        if self.length.counter == 0 {
+            self.length.counter = 64;
+        }
+        self.timer = (2048 - self.frequency) * 4;
+        self.envelope.trigger();
+        let frequency = self.frequency;
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.shadow_frequency = frequency;
+            sweep.timer = if sweep.period == 0 { 8 } else { sweep.period };
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+            if sweep.shift != 0 && self.sweep_frequency() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn sweep_frequency(&self) -> u16 {
+        let sweep = self.sweep.expect("channel has no sweep unit");
+        let delta = sweep.shadow_frequency >> sweep.shift;
+        if sweep.negate {
+            sweep.shadow_frequency - delta
+        } else {
+            sweep.shadow_frequency + delta
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        let mut sweep = match self.sweep {
+            Some(sweep) => sweep,
+            None => return,
+        };
+        if sweep.timer > 0 {
+            sweep.timer -= 1;
+        }
+        if sweep.timer == 0 {
+            sweep.timer = if sweep.period == 0 { 8 } else { sweep.period };
+            if sweep.enabled && sweep.period != 0 {
+                self.sweep = Some(sweep);
+                let new_frequency = self.sweep_frequency();
+                if new_frequency > 2047 {
+                    self.enabled = false;
+                } else if sweep.shift != 0 {
+                    sweep.shadow_frequency = new_frequency;
+                    self.frequency = new_frequency;
+                    self.sweep = Some(sweep);
+                    if self.sweep_frequency() > 2047 {
+                        self.enabled = false;
+                    }
+                }
+            }
+        }
+        self.sweep = Some(sweep);
+    }
+
+    fn tick(&mut self, cycles: u16) {
+        let mut cycles = cycles;
+        while cycles > 0 {
+            if self.timer > cycles {
+                self.timer -= cycles;
+                return;
+            }
+            cycles -= self.timer;
+            self.timer = (2048 - self.frequency) * 4;
+            self.duty_position = (self.duty_position + 1) & 7;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        DUTY_TABLE[self.duty as usize][self.duty_position as usize] * self.envelope.volume
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct WaveChannel {
+    pub enabled: bool,
+    pub dac_enabled: bool,
+    pub volume_code: u8,
+    pub frequency: u16,
+    pub length: LengthCounter,
+    pub wave_ram: [u8; 0x10], // 0xFF30 - 0xFF3F
+    position: u8,
+    sample_buffer: u8,
+    timer: u16,
+}
+
+impl WaveChannel {
+    fn write(&mut self, reg: usize, value: u8) {
+        match reg {
+            0 => {
+                self.dac_enabled = value & 0x80 != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.counter = 256 - value as u16,
+            2 => self.volume_code = (value >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | (((value & 0x07) as u16) << 8);
+                self.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => panic!("invalid wave register: {}", reg),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        if self.length.counter == 0 {
+            self.length.counter = 256;
+        }
+        self.timer = (2048 - self.frequency) * 2;
+        self.position = 0;
+    }
+
+    fn tick(&mut self, cycles: u16) {
+        let mut cycles = cycles;
+        while cycles > 0 {
+            if self.timer > cycles {
+                self.timer -= cycles;
+                return;
+            }
+            cycles -= self.timer;
+            self.timer = (2048 - self.frequency) * 2;
+            self.position = (self.position + 1) & 31;
+            let byte = self.wave_ram[(self.position / 2) as usize];
+            self.sample_buffer = if self.position & 1 == 0 {
+                byte >> 4
+            } else {
+                byte & 0x0F
+            };
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled || self.volume_code == 0 {
+            return 0;
+        }
+        self.sample_buffer >> (self.volume_code - 1)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct NoiseChannel {
+    pub enabled: bool,
+    pub dac_enabled: bool,
+    pub clock_shift: u8,
+    pub width_mode: bool, // 7 bit lfsr when set
+    pub divisor_code: u8,
+    pub length: LengthCounter,
+    pub envelope: Envelope,
+    lfsr: u16,
+    timer: u32, // up to 112 << 13 cycles
+}
+
+impl Default for NoiseChannel {
+    fn default() -> Self {
+        NoiseChannel {
+            enabled: false,
+            dac_enabled: false,
+            clock_shift: 0,
+            width_mode: false,
+            divisor_code: 0,
+            length: LengthCounter::default(),
+            envelope: Envelope::default(),
+            lfsr: 0x7FFF,
+            timer: 0,
+        }
+    }
+}
+
+impl NoiseChannel {
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
+
+    fn write(&mut self, reg: usize, value: u8) {
+        match reg {
+            0 => (),
+            1 => self.length.counter = 64 - (value & 0x3F) as u16,
+            2 => {
+                self.envelope.write(value);
+                self.dac_enabled = value & 0xF8 != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.clock_shift = value >> 4;
+                self.width_mode = value & 0x08 != 0;
+                self.divisor_code = value & 0x07;
+            }
+            4 => {
+                self.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => panic!("invalid noise register: {}", reg),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        if self.length.counter == 0 {
+            self.length.counter = 64;
+        }
+        self.timer = self.period();
+        self.envelope.trigger();
+        self.lfsr = 0x7FFF;
+    }
+
+    // Clock shifts 14 and 15 never clock the lfsr
+    fn tick(&mut self, cycles: u16) {
+        if self.clock_shift >= 14 {
+            return;
+        }
+        let mut cycles = cycles as u32;
+        while cycles > 0 {
+            if self.timer > cycles {
+                self.timer -= cycles;
+                return;
+            }
+            cycles -= self.timer;
+            self.timer = self.period();
+            let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (bit << 14);
+            if self.width_mode {
+                self.lfsr = (self.lfsr & !0x40) | (bit << 6);
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled || self.lfsr & 1 != 0 {
+            return 0;
+        }
+        self.envelope.volume
+    }
+}
+
+#[derive(Clone)]
+pub struct APU {
+    pub power: bool,
+    pub channel1: PulseChannel,
+    pub channel2: PulseChannel,
+    pub channel3: WaveChannel,
+    pub channel4: NoiseChannel,
+    pub nr50: u8, // 0xFF24, master volume
+    pub nr51: u8, // 0xFF25, panning
+    regs: [u8; 0x17], // last written values for read back
+    frame_sequencer_step: u8,
+    output: SampleOutput,
+}
+
+// Frontend side of the apu, survives power cycles and is not part of save states
+#[derive(Clone, Default)]
+struct SampleOutput {
+    muted: [bool; 4],
+    solo: Option<usize>,
+    accumulator: (f32, f32),
+    counter: u32,
+    samples: Vec<f32>, // interleaved left/right at SAMPLE_RATE
+    capture_channels: bool,
+    channel_accumulators: [f32; 4],
+    channel_samples: [Vec<f32>; 4], // mono, before mute, panning and master volume
+}
+
+impl SampleOutput {
+    fn push(buffer: &mut Vec<f32>, samples: &[f32]) {
+        if buffer.len() >= MAX_BUFFERED_SAMPLES {
+            buffer.drain(..MAX_BUFFERED_SAMPLES / 2);
+        }
+        buffer.extend_from_slice(samples);
+    }
+}
+
+impl Default for APU {
+    fn default() -> Self {
+        APU {
+            power: false,
+            channel1: PulseChannel::new(true),
+            channel2: PulseChannel::new(false),
+            channel3: WaveChannel::default(),
+            channel4: NoiseChannel::default(),
+            nr50: 0,
+            nr51: 0,
+            regs: [0; 0x17],
+            frame_sequencer_step: 0,
+            output: SampleOutput::default(),
+        }
+    }
+}
+
+impl APU {
+    pub fn get(&self, addr: usize) -> u8 {
+        match addr {
+            0xFF26 => {
+                ((self.power as u8) << 7)
+                    | READ_MASKS[0x16]
+                    | ((self.channel4.enabled as u8) << 3)
+                    | ((self.channel3.enabled as u8) << 2)
+                    | ((self.channel2.enabled as u8) << 1)
+                    | (self.channel1.enabled as u8)
+            }
+            0xFF10..=0xFF25 => self.regs[addr - 0xFF10] | READ_MASKS[addr - 0xFF10],
+            0xFF27..=0xFF2F => 0xFF,
+            0xFF30..=0xFF3F => self.channel3.wave_ram[addr - 0xFF30],
+            _ => panic!("invalid apu address: {:#x}", addr),
+        }
+    }
+
+    pub fn set(&mut self, addr: usize, value: u8) {
+        match addr {
+            0xFF26 => {
+                let power = value & 0x80 != 0;
+                if self.power && !power {
+                    self.power_off();
+                } else if !self.power && power {
+                    self.frame_sequencer_step = 0;
+                }
+                self.power = power;
+            }
+            // Registers are read only while powered off, wave ram is not
+            0xFF10..=0xFF25 if !self.power => (),
+            0xFF10..=0xFF25 => {
+                self.regs[addr - 0xFF10] = value;
+                match addr {
+                    0xFF10..=0xFF14 => self.channel1.write(addr - 0xFF10, value),
+                    0xFF15..=0xFF19 => self.channel2.write(addr - 0xFF15, value),
+                    0xFF1A..=0xFF1E => self.channel3.write(addr - 0xFF1A, value),
+                    0xFF1F..=0xFF23 => self.channel4.write(addr - 0xFF1F, value),
+                    0xFF24 => self.nr50 = value,
+                    0xFF25 => self.nr51 = value,
+                    _ => (),
+                }
+            }
+            0xFF27..=0xFF2F => (),
+            0xFF30..=0xFF3F => self.channel3.wave_ram[addr - 0xFF30] = value,
+            _ => panic!("invalid apu address: {:#x}", addr),
+        }
+    }
+
+    fn power_off(&mut self) {
+        let wave_ram = self.channel3.wave_ram;
+        *self = APU {
+            output: std::mem::take(&mut self.output),
+            ..Default::default()
+        };
+        self.channel3.wave_ram = wave_ram;
+    }
+
+    // Clocked at 512Hz by the falling edge of DIV bit 4
+    pub fn step_frame_sequencer(&mut self) {
+        if !self.power {
+            return;
+        }
+        if self.frame_sequencer_step & 1 == 0 {
+            if self.channel1.length.clock() {
+                self.channel1.enabled = false;
+            }
+            if self.channel2.length.clock() {
+                self.channel2.enabled = false;
+            }
+            if self.channel3.length.clock() {
+                self.channel3.enabled = false;
+            }
+            if self.channel4.length.clock() {
+                self.channel4.enabled = false;
+            }
+        }
+        if self.frame_sequencer_step == 2 || self.frame_sequencer_step == 6 {
+            self.channel1.clock_sweep();
+        }
+        if self.frame_sequencer_step == 7 {
+            self.channel1.envelope.clock();
+            self.channel2.envelope.clock();
+            self.channel4.envelope.clock();
+        }
+        self.frame_sequencer_step = (self.frame_sequencer_step + 1) & 7;
+    }
+
+    // Advances the channel timers by one machine cycle
+    pub fn tick(&mut self) {
+        if self.power {
+            self.channel1.tick(4);
+            self.channel2.tick(4);
+            self.channel3.tick(4);
+            self.channel4.tick(4);
+        }
+
+        // channel recordings are taken before mute and solo
+        let dac_outputs = self.dac_outputs();
+        let outputs = self.channel_outputs();
+        let (left, right) = self.mix(&outputs);
+        let output = &mut self.output;
+        output.accumulator.0 += left;
+        output.accumulator.1 += right;
+        if output.capture_channels {
+            for (accumulator, sample) in output.channel_accumulators.iter_mut().zip(dac_outputs.iter()) {
+                *accumulator += sample;
+            }
+        }
+        output.counter += 1;
+        if output.counter == SAMPLE_CYCLES {
+            let scale = SAMPLE_CYCLES as f32;
+            let frame = [output.accumulator.0 / scale, output.accumulator.1 / scale];
+            SampleOutput::push(&mut output.samples, &frame);
+            if output.capture_channels {
+                for (buffer, accumulator) in output
+                    .channel_samples
+                    .iter_mut()
+                    .zip(output.channel_accumulators.iter_mut())
+                {
+                    SampleOutput::push(buffer, &[*accumulator / scale]);
+                    *accumulator = 0.0;
+                }
+            }
+            output.accumulator = (0.0, 0.0);
+            output.counter = 0;
+        }
+    }
+
+    // Digital 0 - 15 channel outputs converted by each DAC to -1.0 - 1.0
+    fn dac_outputs(&self) -> [f32; 4] {
+        let dac = |enabled: bool, output: u8| {
+            if enabled {
+                output as f32 / 7.5 - 1.0
+            } else {
+                0.0
+            }
+        };
+        [
+            dac(self.channel1.enabled && self.channel1.dac_enabled, self.channel1.output()),
+            dac(self.channel2.enabled && self.channel2.dac_enabled, self.channel2.output()),
+            dac(self.channel3.enabled && self.channel3.dac_enabled, self.channel3.output()),
+            dac(self.channel4.enabled && self.channel4.dac_enabled, self.channel4.output()),
+        ]
+    }
+
+    // The dac outputs with muted or un-soloed channels read as 0
+    pub fn channel_outputs(&self) -> [f32; 4] {
+        let mut outputs = self.dac_outputs();
+        for (channel, output) in outputs.iter_mut().enumerate() {
+            if !self.channel_audible(channel) {
+                *output = 0.0;
+            }
+        }
+        outputs
+    }
+
+    fn mix(&self, outputs: &[f32; 4]) -> (f32, f32) {
+        if !self.power {
+            return (0.0, 0.0);
+        }
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (i, output) in outputs.iter().enumerate() {
+            if self.nr51 & (0x10 << i) != 0 {
+                left += output;
+            }
+            if self.nr51 & (0x01 << i) != 0 {
+                right += output;
+            }
+        }
+        let left_volume = (((self.nr50 >> 4) & 0x07) + 1) as f32 / 8.0;
+        let right_volume = ((self.nr50 & 0x07) + 1) as f32 / 8.0;
+        (left * left_volume / 4.0, right * right_volume / 4.0)
+    }
+
+    // Hands over everything generated since the last call
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.output.samples)
+    }
+
+    // Channels are numbered 0 - 3: pulse 1, pulse 2, wave and noise
+    pub fn set_muted(&mut self, channel: usize, muted: bool) {
+        self.output.muted[channel] = muted;
+    }
+
+    pub fn is_muted(&self, channel: usize) -> bool {
+        self.output.muted[channel]
+    }
+
+    // While a channel is soloed every other channel is silent, mute flags are kept
+    pub fn set_solo(&mut self, channel: Option<usize>) {
+        self.output.solo = channel;
+    }
+
+    pub fn solo(&self) -> Option<usize> {
+        self.output.solo
+    }
+
+    pub fn channel_audible(&self, channel: usize) -> bool {
+        match self.output.solo {
+            Some(solo) => solo == channel,
+            None => !self.output.muted[channel],
+        }
+    }
+
+    // Also keep a mono stream per channel, read with take_channel_samples
+    pub fn set_channel_capture(&mut self, capture: bool) {
+        self.output.capture_channels = capture;
+        if !capture {
+            self.output.channel_samples = Default::default();
+        }
+    }
+
+    pub fn take_channel_samples(&mut self) -> [Vec<f32>; 4] {
+        std::mem::take(&mut self.output.channel_samples)
+    }
+}
+
+impl SaveState for LengthCounter {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.u16(self.counter);
+        writer.bool(self.enabled);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.counter = reader.u16()?;
+        self.enabled = reader.bool()?;
+        Ok(())
+    }
+}
+
+impl SaveState for Envelope {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bytes(&[
+            self.initial_volume,
+            self.increase as u8,
+            self.period,
+            self.volume,
+            self.timer,
+        ]);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        let mut bytes = [0; 5];
+        reader.bytes(&mut bytes)?;
+        let [initial_volume, increase, period, volume, timer] = bytes;
+        self.initial_volume = initial_volume;
+        self.increase = increase != 0;
+        self.period = period;
+        self.volume = volume;
+        self.timer = timer;
+        Ok(())
+    }
+}
+
+impl SaveState for PulseChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.enabled);
+        writer.bool(self.dac_enabled);
+        writer.u8(self.duty);
+        writer.u16(self.frequency);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+        if let Some(sweep) = self.sweep {
+            writer.bytes(&[sweep.period, sweep.negate as u8, sweep.shift, sweep.enabled as u8]);
+            writer.u16(sweep.shadow_frequency);
+            writer.u8(sweep.timer);
+        }
+        writer.u8(self.duty_position);
+        writer.u16(self.timer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.bool()?;
+        self.dac_enabled = reader.bool()?;
+        self.duty = reader.u8()? & 0x03;
+        self.frequency = reader.u16()? & 0x7FF;
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+        if let Some(sweep) = self.sweep.as_mut() {
+            let mut bytes = [0; 4];
+            reader.bytes(&mut bytes)?;
+            let [period, negate, shift, enabled] = bytes;
+            sweep.period = period;
+            sweep.negate = negate != 0;
+            sweep.shift = shift;
+            sweep.enabled = enabled != 0;
+            sweep.shadow_frequency = reader.u16()?;
+            sweep.timer = reader.u8()?;
+        }
+        self.duty_position = reader.u8()? & 0x07;
+        self.timer = reader.u16()?;
+        Ok(())
+    }
+}
+
+impl SaveState for WaveChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.enabled);
+        writer.bool(self.dac_enabled);
+        writer.u8(self.volume_code);
+        writer.u16(self.frequency);
+        self.length.save_state(writer);
+        writer.bytes(&self.wave_ram);
+        writer.u8(self.position);
+        writer.u8(self.sample_buffer);
+        writer.u16(self.timer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.bool()?;
+        self.dac_enabled = reader.bool()?;
+        self.volume_code = reader.u8()? & 0x03;
+        self.frequency = reader.u16()? & 0x7FF;
+        self.length.load_state(reader)?;
+        reader.bytes(&mut self.wave_ram)?;
+        self.position = reader.u8()? & 31;
+        self.sample_buffer = reader.u8()?;
+        self.timer = reader.u16()?;
+        Ok(())
+    }
+}
+
+impl SaveState for NoiseChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.enabled);
+        writer.bool(self.dac_enabled);
+        writer.u8(self.clock_shift);
+        writer.bool(self.width_mode);
+        writer.u8(self.divisor_code);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+        writer.u16(self.lfsr);
+        writer.u32(self.timer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.bool()?;
+        self.dac_enabled = reader.bool()?;
+        self.clock_shift = reader.u8()? & 0x0F;
+        self.width_mode = reader.bool()?;
+        self.divisor_code = reader.u8()? & 0x07;
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+        self.lfsr = reader.u16()?;
+        self.timer = reader.u32()?;
+        Ok(())
+    }
+}
+
+impl SaveState for APU {
+    // Samples not yet handed to the audio device are not part of the machine state
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.power);
+        self.channel1.save_state(writer);
+        self.channel2.save_state(writer);
+        self.channel3.save_state(writer);
+        self.channel4.save_state(writer);
+        writer.u8(self.nr50);
+        writer.u8(self.nr51);
+        writer.bytes(&self.regs);
+        writer.u8(self.frame_sequencer_step);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.power = reader.bool()?;
+        self.channel1.load_state(reader)?;
+        self.channel2.load_state(reader)?;
+        self.channel3.load_state(reader)?;
+        self.channel4.load_state(reader)?;
+        self.nr50 = reader.u8()?;
+        self.nr51 = reader.u8()?;
+        reader.bytes(&mut self.regs)?;
+        self.frame_sequencer_step = reader.u8()? & 7;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn powered_apu() -> APU {
+        let mut apu = APU::default();
+        apu.set(0xFF26, 0x80);
+        apu
+    }
+
+    #[test]
+    fn test_read_masks() {
+        let mut apu = powered_apu();
+        apu.set(0xFF11, 0x80);
+        assert_eq!(apu.get(0xFF11), 0xBF);
+        apu.set(0xFF13, 0x12);
+        assert_eq!(apu.get(0xFF13), 0xFF);
+        assert_eq!(apu.get(0xFF15), 0xFF);
+        assert_eq!(apu.get(0xFF26), 0xF0);
+        assert_eq!(apu.get(0xFF2A), 0xFF);
+    }
+
+    #[test]
+    fn test_power_off_clears_registers() {
+        let mut apu = powered_apu();
+        apu.set(0xFF12, 0xF0);
+        apu.set(0xFF14, 0x80);
+        apu.set(0xFF30, 0x12);
+        assert_eq!(apu.get(0xFF26), 0xF1);
+        apu.set(0xFF26, 0x00);
+        assert_eq!(apu.get(0xFF26), 0x70);
+        assert_eq!(apu.get(0xFF12), 0x00);
+        apu.set(0xFF12, 0xF0);
+        assert_eq!(apu.get(0xFF12), 0x00);
+        assert_eq!(apu.get(0xFF30), 0x12);
+    }
+
+    #[test]
+    fn test_length_counter_disables_channel() {
+        let mut apu = powered_apu();
+        apu.set(0xFF17, 0xF0);
+        apu.set(0xFF16, 0x3E); // length of 2
+        apu.set(0xFF19, 0xC0);
+        assert!(apu.channel2.enabled);
+        apu.step_frame_sequencer();
+        assert!(apu.channel2.enabled);
+        apu.step_frame_sequencer();
+        apu.step_frame_sequencer();
+        assert!(!apu.channel2.enabled);
+    }
+
+    #[test]
+    fn test_sweep_overflow_on_trigger() {
+        let mut apu = powered_apu();
+        apu.set(0xFF10, 0x11);
+        apu.set(0xFF12, 0xF0);
+        apu.set(0xFF13, 0xFF);
+        apu.set(0xFF14, 0x87);
+        assert!(!apu.channel1.enabled);
+    }
+
+    #[test]
+    fn test_mute_and_solo() {
+        let mut apu = powered_apu();
+        apu.set(0xFF12, 0xF0);
+        apu.set(0xFF14, 0x80);
+        apu.set(0xFF17, 0xF0);
+        apu.set(0xFF19, 0x80);
+        assert_ne!(apu.channel_outputs()[0], 0.0);
+        apu.set_muted(0, true);
+        assert_eq!(apu.channel_outputs()[0], 0.0);
+        assert_ne!(apu.channel_outputs()[1], 0.0);
+        // channel recordings still hear a muted channel
+        apu.set_channel_capture(true);
+        for _ in 0..SAMPLE_CYCLES {
+            apu.tick();
+        }
+        let channel_samples = apu.take_channel_samples();
+        assert_eq!(channel_samples[0].len(), 1);
+        assert_ne!(channel_samples[0][0], 0.0);
+
+        apu.set_solo(Some(1));
+        assert!(!apu.channel_audible(0));
+        assert!(apu.channel_audible(1));
+        assert!(!apu.channel_audible(2));
+        apu.set_solo(None);
+        assert!(apu.is_muted(0));
+
+        // power cycling the apu leaves the frontend settings alone
+        apu.set(0xFF26, 0x00);
+        assert!(apu.is_muted(0));
+    }
+
+    #[test]
+    fn test_envelope() {
+        let mut apu = powered_apu();
+        apu.set(0xFF21, 0x21); // volume 2, decreasing every step
+        apu.set(0xFF23, 0x80);
+        assert_eq!(apu.channel4.envelope.volume, 2);
+        for _ in 0..8 {
+            apu.step_frame_sequencer();
+        }
+        assert_eq!(apu.channel4.envelope.volume, 1);
+    }
+
+    #[test]
+    fn test_noise_clock_shift() {
+        let mut apu = powered_apu();
+        apu.set(0xFF21, 0xF0);
+        apu.set(0xFF22, 0xF0);
+        apu.set(0xFF23, 0x80);
+        for _ in 0..100 {
+            apu.tick();
+        }
+        assert_eq!(apu.channel4.lfsr, 0x7FFF);
+
+        // the longest period still clocks
+        apu.set(0xFF22, 0xD7);
+        apu.set(0xFF23, 0x80);
+        assert_eq!(apu.channel4.timer, 112 << 13);
+        apu.channel4.tick(u16::MAX);
+        assert_eq!(apu.channel4.timer, (112 << 13) - u16::MAX as u32);
+    }
+}
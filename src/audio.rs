@@ -0,0 +1,116 @@
+use rustboy::apu::SAMPLE_RATE;
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::Sdl;
+
+const DEVICE_RATE: i32 = 48000;
+const DEVICE_BUFFER_FRAMES: u16 = 1024;
+const TARGET_LATENCY_FRAMES: u32 = 4096; // about 85ms queued at 48kHz
+const MAX_RATE_DELTA: f64 = 0.005;
+
+// Linear interpolation of interleaved stereo frames from the apu rate to the device rate.
+// rate_adjust scales how many output frames each input frame produces
+pub struct Resampler {
+    step: f64, // input frames per output frame before adjustment
+    pub rate_adjust: f64,
+    position: f64,
+    previous: (f32, f32),
+}
+
+impl Resampler {
+    pub fn new(input_rate: u32, output_rate: u32) -> Resampler {
+        Resampler {
+            step: input_rate as f64 / output_rate as f64,
+            rate_adjust: 1.0,
+            position: 0.0,
+            previous: (0.0, 0.0),
+        }
+    }
+
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        let step = self.step / self.rate_adjust;
+        for frame in input.chunks_exact(2) {
+            let current = (frame[0], frame[1]);
+            while self.position < 1.0 {
+                let t = self.position as f32;
+                output.push(self.previous.0 + (current.0 - self.previous.0) * t);
+                output.push(self.previous.1 + (current.1 - self.previous.1) * t);
+                self.position += step;
+            }
+            self.position -= 1.0;
+            self.previous = current;
+        }
+    }
+}
+
+// Streams apu samples to the default device. The emulator is paced by the frame timer
+// rather than the sound card, so the resampling ratio is nudged to keep the queue near
+// its target fill instead of slowly running dry or piling up
+pub struct AudioOutput {
+    queue: AudioQueue<f32>,
+    resampler: Resampler,
+    buffer: Vec<f32>,
+}
+
+impl AudioOutput {
+    pub fn new(sdl_context: &Sdl) -> Result<AudioOutput, String> {
+        let audio_subsystem = sdl_context.audio()?;
+        let desired = AudioSpecDesired {
+            freq: Some(DEVICE_RATE),
+            channels: Some(2),
+            samples: Some(DEVICE_BUFFER_FRAMES),
+        };
+        let queue: AudioQueue<f32> = audio_subsystem.open_queue(None, &desired)?;
+        if queue.spec().channels != 2 {
+            return Err(format!("unsupported channel count: {}", queue.spec().channels));
+        }
+        let resampler = Resampler::new(SAMPLE_RATE, queue.spec().freq as u32);
+        queue.resume();
+        Ok(AudioOutput {
+            queue,
+            resampler,
+            buffer: Vec::new(),
+        })
+    }
+
+    // Stereo frames waiting in the device queue
+    fn queued_frames(&self) -> u32 {
+        self.queue.size() / (2 * std::mem::size_of::<f32>() as u32)
+    }
+
+    pub fn queue_samples(&mut self, samples: &[f32]) {
+        let queued = self.queued_frames();
+        if queued > TARGET_LATENCY_FRAMES * 4 {
+            // far behind, e.g. after the lcd was off or a state was loaded
+            self.queue.clear();
+        }
+        let fill = queued.min(TARGET_LATENCY_FRAMES * 2) as f64 / TARGET_LATENCY_FRAMES as f64;
+        self.resampler.rate_adjust = 1.0 + MAX_RATE_DELTA * (1.0 - fill);
+
+        self.buffer.clear();
+        self.resampler.process(samples, &mut self.buffer);
+        if !self.queue.queue(&self.buffer) {
+            println!("Could not queue audio: {}", sdl2::get_error());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resample_ratio() {
+        let mut resampler = Resampler::new(65536, 48000);
+        let input = vec![0.5; 65536 * 2];
+        let mut output = Vec::new();
+        resampler.process(&input, &mut output);
+        assert!((output.len() as i32 / 2 - 48000).abs() <= 1);
+        assert!(output[200..].iter().all(|&sample| sample == 0.5));
+
+        // a nearly empty queue stretches the same input into more frames
+        let mut output = Vec::new();
+        resampler.rate_adjust = 1.0 + MAX_RATE_DELTA;
+        resampler.process(&input, &mut output);
+        assert!((output.len() as i32 / 2 - 48240).abs() <= 1);
+    }
+}
@@ -0,0 +1,520 @@
+use crate::mbc::{Mbc, Rtc, RtcClock, MBC2_RAM_SIZE, RTC_FOOTER_SIZE};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+use std::convert::TryFrom;
+use std::fmt;
+
+const HEADER_END: usize = 0x0150;
+const TITLE_START: usize = 0x0134;
+const MANUFACTURER_START: usize = 0x013F;
+const CGB_FLAG: usize = 0x0143;
+const NEW_LICENSEE_START: usize = 0x0144;
+const SGB_FLAG: usize = 0x0146;
+const CARTRIDGE_TYPE: usize = 0x0147;
+const ROM_SIZE: usize = 0x0148;
+const RAM_SIZE: usize = 0x0149;
+const DESTINATION: usize = 0x014A;
+const OLD_LICENSEE: usize = 0x014B;
+const VERSION: usize = 0x014C;
+const HEADER_CHECKSUM: usize = 0x014D;
+const GLOBAL_CHECKSUM: usize = 0x014E;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CartridgeError {
+    Truncated { len: usize },
+    RomSizeMismatch { expected: usize, actual: usize },
+    InvalidRomSize(u8),
+    InvalidRamSize(u8),
+    UnknownCartridgeType(u8),
+    UnsupportedMapper(Mapper),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::Truncated { len } => write!(
+                f,
+                "rom is {:#x} bytes, too short to contain a cartridge header",
+                len
+            ),
+            CartridgeError::RomSizeMismatch { expected, actual } => write!(
+                f,
+                "header declares {:#x} bytes of rom but the file is only {:#x} bytes",
+                expected, actual
+            ),
+            CartridgeError::InvalidRomSize(code) => write!(f, "invalid rom size code: {:#x}", code),
+            CartridgeError::InvalidRamSize(code) => write!(f, "invalid ram size code: {:#x}", code),
+            CartridgeError::UnknownCartridgeType(code) => {
+                write!(f, "unknown cartridge type: {:#x}", code)
+            }
+            CartridgeError::UnsupportedMapper(mapper) => {
+                write!(f, "unsupported memory bank controller: {:?}", mapper)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Mapper {
+    #[default]
+    None,
+    Mbc1,
+    Mbc2,
+    Mmm01,
+    Mbc3,
+    Mbc5,
+    Mbc6,
+    Mbc7,
+    PocketCamera,
+    Tama5,
+    HuC3,
+    HuC1,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CartridgeType {
+    pub code: u8,
+    pub mapper: Mapper,
+    pub ram: bool,
+    pub battery: bool,
+    pub timer: bool,
+    pub rumble: bool,
+}
+
+impl TryFrom<u8> for CartridgeType {
+    type Error = CartridgeError;
+
+    fn try_from(code: u8) -> Result<Self, Self::Error> {
+        let (mapper, ram, battery, timer, rumble) = match code {
+            0x00 => (Mapper::None, false, false, false, false),
+            0x01 => (Mapper::Mbc1, false, false, false, false),
+            0x02 => (Mapper::Mbc1, true, false, false, false),
+            0x03 => (Mapper::Mbc1, true, true, false, false),
+            0x05 => (Mapper::Mbc2, false, false, false, false),
+            0x06 => (Mapper::Mbc2, false, true, false, false),
+            0x08 => (Mapper::None, true, false, false, false),
+            0x09 => (Mapper::None, true, true, false, false),
+            0x0B => (Mapper::Mmm01, false, false, false, false),
+            0x0C => (Mapper::Mmm01, true, false, false, false),
+            0x0D => (Mapper::Mmm01, true, true, false, false),
+            0x0F => (Mapper::Mbc3, false, true, true, false),
+            0x10 => (Mapper::Mbc3, true, true, true, false),
+            0x11 => (Mapper::Mbc3, false, false, false, false),
+            0x12 => (Mapper::Mbc3, true, false, false, false),
+            0x13 => (Mapper::Mbc3, true, true, false, false),
+            0x19 => (Mapper::Mbc5, false, false, false, false),
+            0x1A => (Mapper::Mbc5, true, false, false, false),
+            0x1B => (Mapper::Mbc5, true, true, false, false),
+            0x1C => (Mapper::Mbc5, false, false, false, true),
+            0x1D => (Mapper::Mbc5, true, false, false, true),
+            0x1E => (Mapper::Mbc5, true, true, false, true),
+            0x20 => (Mapper::Mbc6, true, true, false, false),
+            0x22 => (Mapper::Mbc7, true, true, false, true),
+            0xFC => (Mapper::PocketCamera, true, true, false, false),
+            0xFD => (Mapper::Tama5, true, true, true, false),
+            0xFE => (Mapper::HuC3, true, true, true, false),
+            0xFF => (Mapper::HuC1, true, true, false, false),
+            _ => return Err(CartridgeError::UnknownCartridgeType(code)),
+        };
+        Ok(CartridgeType {
+            code,
+            mapper,
+            ram,
+            battery,
+            timer,
+            rumble,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum CgbFlag {
+    #[default]
+    Dmg,
+    CgbEnhanced, // 0x80
+    CgbOnly,     // 0xC0
+}
+
+impl From<u8> for CgbFlag {
+    fn from(val: u8) -> Self {
+        match val {
+            0x80 => CgbFlag::CgbEnhanced,
+            0xC0 => CgbFlag::CgbOnly,
+            _ => CgbFlag::Dmg,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Destination {
+    #[default]
+    Japan,
+    Overseas,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CartridgeHeader {
+    pub title: String,
+    pub manufacturer_code: String, // only present on CGB era cartridges
+    pub cgb_flag: CgbFlag,
+    pub new_licensee_code: String,
+    pub sgb_flag: bool,
+    pub cartridge_type: CartridgeType,
+    pub rom_size: usize,
+    pub ram_size: usize,
+    pub destination: Destination,
+    pub old_licensee_code: u8,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub computed_header_checksum: u8, // only the dmg boot rom refuses a mismatch
+    pub global_checksum: u16,
+}
+
+impl CartridgeHeader {
+    // The super game boy only listens to cartridges that ask for it with the new
+    // licensee code scheme
+    pub fn supports_sgb(&self) -> bool {
+        self.sgb_flag && self.old_licensee_code == 0x33
+    }
+
+    pub fn parse(rom: &[u8]) -> Result<CartridgeHeader, CartridgeError> {
+        if rom.len() < HEADER_END {
+            return Err(CartridgeError::Truncated { len: rom.len() });
+        }
+
+        let cgb_flag = CgbFlag::from(rom[CGB_FLAG]);
+        let (title_end, manufacturer_code) = if cgb_flag == CgbFlag::Dmg {
+            (CGB_FLAG + 1, String::new())
+        } else {
+            (
+                MANUFACTURER_START,
+                header_string(&rom[MANUFACTURER_START..CGB_FLAG]),
+            )
+        };
+
+        let rom_size = match rom[ROM_SIZE] {
+            n @ 0x00..=0x08 => 0x8000 << n,
+            n => return Err(CartridgeError::InvalidRomSize(n)),
+        };
+        let ram_size = match rom[RAM_SIZE] {
+            0x00 => 0,
+            0x01 => 0x800,
+            0x02 => 0x2000,
+            0x03 => 0x8000,
+            0x04 => 0x20000,
+            0x05 => 0x10000,
+            n => return Err(CartridgeError::InvalidRamSize(n)),
+        };
+
+        Ok(CartridgeHeader {
+            title: header_string(&rom[TITLE_START..title_end]),
+            manufacturer_code,
+            cgb_flag,
+            new_licensee_code: header_string(&rom[NEW_LICENSEE_START..SGB_FLAG]),
+            sgb_flag: rom[SGB_FLAG] == 0x03,
+            cartridge_type: CartridgeType::try_from(rom[CARTRIDGE_TYPE])?,
+            rom_size,
+            ram_size,
+            destination: if rom[DESTINATION] == 0 {
+                Destination::Japan
+            } else {
+                Destination::Overseas
+            },
+            old_licensee_code: rom[OLD_LICENSEE],
+            version: rom[VERSION],
+            header_checksum: rom[HEADER_CHECKSUM],
+            computed_header_checksum: header_checksum(rom),
+            global_checksum: (rom[GLOBAL_CHECKSUM] as u16) << 8 | rom[GLOBAL_CHECKSUM + 1] as u16,
+        })
+    }
+}
+
+// Same algorithm the boot rom uses over 0x0134 - 0x014C
+fn header_checksum(rom: &[u8]) -> u8 {
+    rom[TITLE_START..HEADER_CHECKSUM]
+        .iter()
+        .fold(0u8, |acc, byte| acc.wrapping_sub(*byte).wrapping_sub(1))
+}
+
+fn header_string(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .take_while(|b| **b != 0)
+        .filter(|b| b.is_ascii_graphic() || **b == b' ')
+        .map(|b| *b as char)
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+pub struct Cartridge {
+    pub header: CartridgeHeader,
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    mbc: Mbc,
+    pub ram_dirty: bool, // set on every ram write, cleared by whoever persists it
+}
+
+impl Cartridge {
+    pub fn new(rom: Vec<u8>) -> Result<Cartridge, CartridgeError> {
+        let header = CartridgeHeader::parse(&rom)?;
+        if rom.len() < header.rom_size {
+            return Err(CartridgeError::RomSizeMismatch {
+                expected: header.rom_size,
+                actual: rom.len(),
+            });
+        }
+        let mbc = Mbc::new(&header, &rom)?;
+        let ram_size = if header.cartridge_type.mapper == Mapper::Mbc2 {
+            MBC2_RAM_SIZE
+        } else if header.cartridge_type.ram {
+            header.ram_size
+        } else {
+            0
+        };
+        Ok(Cartridge {
+            header,
+            rom,
+            ram: vec![0; ram_size],
+            mbc,
+            ram_dirty: false,
+        })
+    }
+
+    // The global checksum is never verified by hardware, so a mismatch is not an error
+    pub fn global_checksum_valid(&self) -> bool {
+        let sum = self
+            .rom
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != GLOBAL_CHECKSUM && *i != GLOBAL_CHECKSUM + 1)
+            .fold(0u16, |acc, (_, byte)| acc.wrapping_add(*byte as u16));
+        sum == self.header.global_checksum
+    }
+
+    // Lets the caller drive the MBC3 clock, e.g. deterministically from tests
+    pub fn set_rtc_clock(&mut self, clock: Box<dyn RtcClock>) {
+        if let Some(rtc) = self.mbc.rtc_mut() {
+            rtc.set_clock(clock);
+        }
+    }
+
+    // Whether the rumble motor is currently being driven, always false without one
+    pub fn rumble_active(&self) -> bool {
+        self.mbc.rumble()
+    }
+
+    pub fn rtc(&self) -> Option<&Rtc> {
+        self.mbc.rtc()
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x7FFF => self.mbc.read_rom(&self.rom, addr),
+            0xA000..=0xBFFF => self.mbc.read_ram(&self.ram, addr),
+            _ => panic!("invalid cartridge address: {:#x}", addr),
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x0000..=0x7FFF => self.mbc.write_rom(addr, data),
+            0xA000..=0xBFFF => {
+                self.mbc.write_ram(&mut self.ram, addr, data);
+                self.ram_dirty = true;
+            }
+            _ => panic!("invalid cartridge address: {:#x}", addr),
+        }
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.header.cartridge_type.battery
+    }
+
+    // Contents of a .sav file: external ram, then the rtc footer for clock carts
+    pub fn save_data(&self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        if let Some(rtc) = self.mbc.rtc() {
+            data.extend_from_slice(&rtc.to_footer());
+        }
+        data
+    }
+
+    pub fn load_save_data(&mut self, data: &[u8]) {
+        let ram_len = self.ram.len().min(data.len());
+        self.ram[..ram_len].copy_from_slice(&data[..ram_len]);
+        if let Some(rtc) = self.mbc.rtc_mut() {
+            let footer = &data[ram_len..];
+            if footer.len() <= RTC_FOOTER_SIZE {
+                rtc.load_footer(footer);
+            }
+        }
+    }
+}
+
+impl SaveState for Cartridge {
+    // The rom itself is not part of the state, only the ram and bank registers
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.sized_bytes(&self.ram);
+        self.mbc.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.sized_bytes(&mut self.ram)?;
+        self.ram_dirty = true;
+        self.mbc.load_state(reader)
+    }
+}
+
+impl Default for Cartridge {
+    fn default() -> Self {
+        Cartridge {
+            header: CartridgeHeader {
+                rom_size: 0x8000,
+                ..Default::default()
+            },
+            rom: vec![0; 0x8000],
+            ram: Vec::new(),
+            mbc: Mbc::RomOnly,
+            ram_dirty: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_rom(cartridge_type: u8, rom_size: u8, ram_size: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000 << rom_size];
+        rom[TITLE_START..TITLE_START + 6].copy_from_slice(b"TETRIS");
+        rom[CARTRIDGE_TYPE] = cartridge_type;
+        rom[ROM_SIZE] = rom_size;
+        rom[RAM_SIZE] = ram_size;
+        rom[DESTINATION] = 1;
+        rom[OLD_LICENSEE] = 0x01;
+        rom[HEADER_CHECKSUM] = header_checksum(&rom);
+        rom
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let rom = make_rom(0x09, 0, 2);
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.title, "TETRIS");
+        assert_eq!(header.cgb_flag, CgbFlag::Dmg);
+        assert_eq!(header.cartridge_type.mapper, Mapper::None);
+        assert!(header.cartridge_type.ram && header.cartridge_type.battery);
+        assert_eq!(header.rom_size, 0x8000);
+        assert_eq!(header.ram_size, 0x2000);
+        assert_eq!(header.destination, Destination::Overseas);
+    }
+
+    #[test]
+    fn test_cgb_title_and_manufacturer() {
+        let mut rom = make_rom(0x00, 0, 0);
+        rom[MANUFACTURER_START..CGB_FLAG].copy_from_slice(b"ABCD");
+        rom[CGB_FLAG] = 0xC0;
+        rom[HEADER_CHECKSUM] = header_checksum(&rom);
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.title, "TETRIS");
+        assert_eq!(header.manufacturer_code, "ABCD");
+        assert_eq!(header.cgb_flag, CgbFlag::CgbOnly);
+    }
+
+    #[test]
+    fn test_invalid_headers() {
+        assert_eq!(
+            Cartridge::new(vec![0; 0x100]).err(),
+            Some(CartridgeError::Truncated { len: 0x100 })
+        );
+
+        let mut rom = make_rom(0x00, 0, 0);
+        rom[HEADER_CHECKSUM] = rom[HEADER_CHECKSUM].wrapping_add(1);
+        let cartridge = Cartridge::new(rom).unwrap();
+        assert_ne!(cartridge.header.header_checksum, cartridge.header.computed_header_checksum);
+
+        let mut rom = make_rom(0x00, 1, 0);
+        rom.truncate(0x8000);
+        assert_eq!(
+            Cartridge::new(rom).err(),
+            Some(CartridgeError::RomSizeMismatch {
+                expected: 0x10000,
+                actual: 0x8000
+            })
+        );
+
+        assert_eq!(
+            Cartridge::new(make_rom(0x42, 0, 0)).err(),
+            Some(CartridgeError::UnknownCartridgeType(0x42))
+        );
+        assert_eq!(
+            Cartridge::new(make_rom(0x22, 0, 0)).err(),
+            Some(CartridgeError::UnsupportedMapper(Mapper::Mbc7))
+        );
+    }
+
+    #[test]
+    fn test_mbc1_bus() {
+        let mut rom = make_rom(0x03, 2, 3);
+        rom[0x3 * 0x4000] = 0x56;
+        let mut cartridge = Cartridge::new(rom).unwrap();
+        cartridge.write(0x2000, 0x03);
+        assert_eq!(cartridge.read(0x4000), 0x56);
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write(0xB000, 0x78);
+        assert_eq!(cartridge.read(0xB000), 0x78);
+    }
+
+    #[test]
+    fn test_mbc3_injected_clock() {
+        use crate::mbc::TestClock;
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let mut cartridge = Cartridge::new(make_rom(0x10, 2, 3)).unwrap();
+        let time = Rc::new(Cell::new(100));
+        cartridge.set_rtc_clock(Box::new(TestClock(time.clone())));
+        time.set(100 + 61);
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write(0x4000, 0x09);
+        cartridge.write(0x6000, 0x00);
+        cartridge.write(0x6000, 0x01);
+        assert_eq!(cartridge.read(0xA000), 1);
+    }
+
+    #[test]
+    fn test_save_data_with_rtc_footer() {
+        let mut cartridge = Cartridge::new(make_rom(0x10, 2, 2)).unwrap();
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write(0xA000, 0x99);
+        assert!(cartridge.ram_dirty);
+        let data = cartridge.save_data();
+        assert_eq!(data.len(), 0x2000 + RTC_FOOTER_SIZE);
+
+        let mut loaded = Cartridge::new(make_rom(0x10, 2, 2)).unwrap();
+        loaded.load_save_data(&data);
+        loaded.write(0x0000, 0x0A);
+        assert_eq!(loaded.read(0xA000), 0x99);
+    }
+
+    #[test]
+    fn test_rom_only_bus() {
+        let mut rom = make_rom(0x08, 0, 2);
+        rom[0x4000] = 0x12;
+        let mut cartridge = Cartridge::new(rom).unwrap();
+        cartridge.write(0x4000, 0xFF);
+        assert_eq!(cartridge.read(0x4000), 0x12);
+        cartridge.write(0xA010, 0x34);
+        assert_eq!(cartridge.read(0xA010), 0x34);
+    }
+}
@@ -0,0 +1,176 @@
+use rustboy::gameboy::Model;
+use rustboy::ppu::Palette;
+use std::path::PathBuf;
+
+pub const DEFAULT_BOOT_ROM: &str = "bootix_dmg.bin";
+const DEFAULT_SCALE: u32 = 8;
+
+pub const USAGE: &str = "usage: rustboy [options] <rom>
+
+options:
+    --boot-rom <path>       boot rom to run first, defaults to bootix_dmg.bin if present
+    --skip-boot             start at the cartridge entry point without a boot rom
+    --model <name>          dmg, mgb, sgb or cgb, defaults to the cartridge's
+                            preference
+    --scale <n>             window scale factor, 1 - 16 (default 8)
+    --palette <name>        green, gray or pocket (default green)
+    --mute                  do not open an audio device
+    --headless              run without a window as fast as possible, needs --frames
+    --frames <n>            exit after n frames
+    --screenshot <path>     write the last frame to a bmp file on exit
+    --record-audio <path>   record the mixed audio output to a wav file
+    -h, --help              print this message";
+
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub rom: PathBuf,
+    pub boot_rom: Option<PathBuf>,
+    pub skip_boot: bool,
+    pub model: Option<Model>,
+    pub scale: u32,
+    pub palette: Palette,
+    pub mute: bool,
+    pub headless: bool,
+    pub frame_limit: Option<u32>,
+    pub screenshot: Option<PathBuf>,
+    pub record_audio: Option<PathBuf>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CliError {
+    Help,
+    Usage(String),
+}
+
+impl Options {
+    // Arguments without the program name
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Options, CliError> {
+        let mut rom = None;
+        let mut options = Options {
+            rom: PathBuf::new(),
+            boot_rom: None,
+            skip_boot: false,
+            model: None,
+            scale: DEFAULT_SCALE,
+            palette: Palette::default(),
+            mute: false,
+            headless: false,
+            frame_limit: None,
+            screenshot: None,
+            record_audio: None,
+        };
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .ok_or_else(|| CliError::Usage(format!("{} needs a value", name)))
+            };
+            match arg.as_str() {
+                "-h" | "--help" => return Err(CliError::Help),
+                "--boot-rom" => options.boot_rom = Some(PathBuf::from(value(&arg)?)),
+                "--skip-boot" => options.skip_boot = true,
+                "--model" => {
+                    let name = value(&arg)?;
+                    let model = Model::from_name(&name).ok_or_else(|| {
+                        CliError::Usage(format!(
+                            "unknown model {}, expected one of: {}",
+                            name,
+                            Model::NAMES.join(", ")
+                        ))
+                    })?;
+                    options.model = Some(model);
+                }
+                "--scale" => {
+                    options.scale = match value(&arg)?.parse() {
+                        Ok(scale @ 1..=16) => scale,
+                        _ => return Err(CliError::Usage("--scale must be between 1 and 16".to_string())),
+                    }
+                }
+                "--palette" => {
+                    let name = value(&arg)?;
+                    options.palette = Palette::from_name(&name).ok_or_else(|| {
+                        CliError::Usage(format!(
+                            "unknown palette {}, expected one of: {}",
+                            name,
+                            Palette::NAMES.join(", ")
+                        ))
+                    })?;
+                }
+                "--mute" => options.mute = true,
+                "--headless" => options.headless = true,
+                "--frames" => {
+                    let frames = value(&arg)?;
+                    let frames = frames
+                        .parse()
+                        .map_err(|_| CliError::Usage(format!("invalid frame count: {}", frames)))?;
+                    options.frame_limit = Some(frames);
+                }
+                "--screenshot" => options.screenshot = Some(PathBuf::from(value(&arg)?)),
+                "--record-audio" => options.record_audio = Some(PathBuf::from(value(&arg)?)),
+                flag if flag.starts_with('-') => {
+                    return Err(CliError::Usage(format!("unknown option: {}", flag)))
+                }
+                path => {
+                    if rom.is_some() {
+                        return Err(CliError::Usage(format!("unexpected argument: {}", path)));
+                    }
+                    rom = Some(PathBuf::from(path));
+                }
+            }
+        }
+
+        options.rom = rom.ok_or_else(|| CliError::Usage("no rom given".to_string()))?;
+        if options.skip_boot && options.boot_rom.is_some() {
+            return Err(CliError::Usage("--boot-rom and --skip-boot exclude each other".to_string()));
+        }
+        // without a window there is no other way to stop and write the save and screenshot
+        if options.headless && options.frame_limit.is_none() {
+            return Err(CliError::Usage("--headless needs --frames".to_string()));
+        }
+        Ok(options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, CliError> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let options = parse(&[
+            "--scale", "3", "--palette", "gray", "--headless", "--frames", "600", "game.gb",
+            "--screenshot", "out.bmp", "--skip-boot", "--model", "mgb",
+        ])
+        .unwrap();
+        assert_eq!(options.rom, PathBuf::from("game.gb"));
+        assert_eq!(options.scale, 3);
+        assert_eq!(options.palette, Palette::GRAY);
+        assert!(options.headless && options.skip_boot && !options.mute);
+        assert_eq!(options.frame_limit, Some(600));
+        assert_eq!(options.model, Some(Model::Mgb));
+        assert_eq!(options.screenshot, Some(PathBuf::from("out.bmp")));
+
+        let options = parse(&["game.gb"]).unwrap();
+        assert_eq!(options.scale, DEFAULT_SCALE);
+        assert_eq!(options.boot_rom, None);
+        assert_eq!(parse(&["--model", "sgb", "game.gb"]).unwrap().model, Some(Model::Sgb));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(parse(&["--help", "game.gb"]), Err(CliError::Help));
+        assert!(matches!(parse(&[]), Err(CliError::Usage(_))));
+        assert!(matches!(parse(&["game.gb", "--scale"]), Err(CliError::Usage(_))));
+        assert!(matches!(parse(&["game.gb", "--scale", "0"]), Err(CliError::Usage(_))));
+        assert!(matches!(parse(&["game.gb", "--palette", "red"]), Err(CliError::Usage(_))));
+        assert!(matches!(parse(&["game.gb", "--fast"]), Err(CliError::Usage(_))));
+        assert!(matches!(parse(&["game.gb", "--model", "gba"]), Err(CliError::Usage(_))));
+        assert!(matches!(parse(&["game.gb", "other.gb"]), Err(CliError::Usage(_))));
+        assert!(matches!(parse(&["game.gb", "--headless"]), Err(CliError::Usage(_))));
+    }
+}
@@ -1,5 +1,7 @@
 #![allow(non_snake_case)]
-use crate::{instructions::{instruction_decode, StagePassThrough, CC}, ppu::{Mode, PPU}, register_maps::{InterruptEnable, InterruptFlag}};
+use crate::{cartridge::Cartridge, instructions::{instruction_decode, StagePassThrough, CC}, ppu::{Mode, PPU}, register_maps::{InterruptEnable, InterruptFlag}};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+use std::convert::TryFrom;
 use std::ops::{Index, IndexMut};
 
 pub struct CPU {
@@ -72,6 +74,10 @@ impl CPU {
         let mut instruction = self.pass_in.0;
 	let mut pass = self.pass_in.1;
 	self.ppu.io_registers.tick_timer();
+	if self.ppu.io_registers.hdma.stall_cycles > 0 {
+	    self.ppu.io_registers.hdma.stall_cycles -= 1;
+	    return;
+	}
 	if instruction == 0x76 { // HALT
 	    //println!("halt!");
 	    if u8::from(self.ppu.io_registers.interrupt_flag) & u8::from(self.ie) != 0 {
@@ -100,9 +106,52 @@ impl CPU {
 	self.pass_in = (instruction, pass);
     }
 
+    // One cpu machine cycle with the ppu alongside, which keeps its pace in double speed
     pub fn step(&mut self, debug: bool) {
 	self.step_cpu(debug);
-	
+	let dots = if self.ppu.io_registers.key1.double_speed { 2 } else { 4 };
+	if self.ppu.tick(dots) {
+	    self.hblank_dma();
+	}
+	self.mode = self.ppu.mode;
+    }
+
+    // Copies the next 16 bytes of a vram dma into the current vram bank and stalls the
+    // cpu for it, true once the transfer is done
+    fn vram_dma_block(&mut self) -> bool {
+	let hdma = self.ppu.io_registers.hdma;
+	for i in 0..0x10 {
+	    let source = match hdma.source.wrapping_add(i) {
+		addr @ 0xE000..=0xFFFF => addr - 0x4000, // reads external ram
+		addr => addr,
+	    };
+	    let data = self.get_data_from_addr(source);
+	    let offset = self.ppu.vram_offset(0x8000 + (hdma.destination + i) as usize);
+	    self.ppu.vram[offset] = data;
+	}
+	let io_registers = &mut self.ppu.io_registers;
+	io_registers.hdma.stall_cycles += if io_registers.key1.double_speed { 16 } else { 8 };
+	io_registers.hdma.block_done()
+    }
+
+    fn write_hdma_control(&mut self, data: u8) {
+	if self.ppu.io_registers.hdma.write_control(data) {
+	    while !self.vram_dma_block() {}
+	} else if self.ppu.io_registers.hdma.hblank_active && self.ppu.io_registers.lcdc.lcd_ppu_enable == 0 {
+	    // with the lcd off the first block goes right away
+	    self.vram_dma_block();
+	}
+    }
+
+    // Run at the start of every h-blank
+    pub fn hblank_dma(&mut self) {
+	if self.ppu.io_registers.hdma.hblank_active {
+	    self.vram_dma_block();
+	}
+    }
+
+    fn wram_offset(&self, addr: usize) -> usize {
+	self.ppu.io_registers.wram_bank() * 0x1000 + addr - 0xD000
     }
 
     pub fn read(&self) -> u8 {
@@ -112,17 +161,17 @@ impl CPU {
 		if self.memory.use_boot {
 		    self.memory.boot_rom[addr]
 		} else {
-		    self.memory.rom_bank0[addr]
+		    self.memory.cartridge.read(addr as u16)
 		}
 	    }
-	    0x0100..=0x3FFF => self.memory.rom_bank0[addr],
-            0x4000..=0x7FFF => self.memory.rom_bank1[addr - 0x4000],
+	    0x0100..=0x7FFF => self.memory.cartridge.read(addr as u16),
             0x8000..=0x9FFF => match self.mode {
 		Mode::Mode3 => 0xFF,
-		_ => self.ppu.vram[addr - 0x8000],
+		_ => self.ppu.vram[self.ppu.vram_offset(addr)],
 	    },
-            0xA000..=0xBFFF => self.memory.external_ram[addr - 0xA000],
-            0xC000..=0xDFFF => self.memory.working_ram[addr - 0xC000],
+            0xA000..=0xBFFF => self.memory.cartridge.read(addr as u16),
+            0xC000..=0xCFFF => self.memory.working_ram[addr - 0xC000],
+            0xD000..=0xDFFF => self.memory.working_ram[self.wram_offset(addr)],
             0xE000..=0xFDFF => self.memory.echo_ram[addr - 0xE000],
             0xFE00..=0xFE9F => match self.mode {
 		Mode::Off | Mode::Mode0 | Mode::Mode1 => self.ppu.oam.get(addr),
@@ -142,37 +191,44 @@ impl CPU {
 		if self.memory.use_boot {
 		    self.memory.boot_rom[addr]
 		} else {
-		    self.memory.rom_bank0[addr]
+		    self.memory.cartridge.read(addr as u16)
 		}
 	    }
-	    0x0100..=0x3FFF => self.memory.rom_bank0[addr],
-            0x4000..=0x7FFF => self.memory.rom_bank1[addr - 0x4000],
+	    0x0100..=0x7FFF => self.memory.cartridge.read(addr as u16),
             0x8000..=0x9FFF => match self.mode {
 		Mode::Mode3 => 0xFF,
-		_ => self.ppu.vram[addr - 0x8000],
+		_ => self.ppu.vram[self.ppu.vram_offset(addr)],
 	    },
-            0xA000..=0xBFFF => self.memory.external_ram[addr - 0xA000],
-            0xC000..=0xDFFF => self.memory.working_ram[addr - 0xC000],
+            0xA000..=0xBFFF => self.memory.cartridge.read(addr as u16),
+            0xC000..=0xCFFF => self.memory.working_ram[addr - 0xC000],
+            0xD000..=0xDFFF => self.memory.working_ram[self.wram_offset(addr)],
 	    _ => panic!("shouldnt use this function"),
 	}
     }
     pub fn write_data(&mut self, data: u8) {
         let addr = self.addr_bus as usize;
         match addr {
-            0x0000..=0x3FFF => (),
-            0x4000..=0x7FFF => (),
+            0x0000..=0x7FFF => self.memory.cartridge.write(addr as u16, data),
             0x8000..=0x9FFF => match self.mode {
 		Mode::Mode3 => (),
-		_ => self.ppu.vram[addr - 0x8000] = data,
+		_ => {
+		    let offset = self.ppu.vram_offset(addr);
+		    self.ppu.vram[offset] = data;
+		}
 	    },
-            0xA000..=0xBFFF => self.memory.external_ram[addr - 0xA000] = data,
-            0xC000..=0xDFFF => self.memory.working_ram[addr - 0xC000] = data,
+            0xA000..=0xBFFF => self.memory.cartridge.write(addr as u16, data),
+            0xC000..=0xCFFF => self.memory.working_ram[addr - 0xC000] = data,
+            0xD000..=0xDFFF => {
+		let offset = self.wram_offset(addr);
+		self.memory.working_ram[offset] = data;
+	    }
             0xE000..=0xFDFF => self.memory.echo_ram[addr - 0xE000] = data,
             0xFE00..=0xFE9F => match self.mode {
 		Mode::Off | Mode::Mode0 | Mode::Mode1 => self.ppu.oam.set(addr, data),
 		_ => (),
 	    },
             0xFEA0..=0xFEFF => (),
+            0xFF55 if self.ppu.io_registers.cgb_mode => self.write_hdma_control(data),
             0xFF00..=0xFF7F => {
 		if addr == 0xFF50 && data != 0 {
 		    self.memory.use_boot = false;
@@ -217,6 +273,34 @@ impl CPU {
     }
 }
 
+impl SaveState for CPU {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.reg_file.save_state(writer);
+        writer.u16(self.addr_bus);
+        writer.u16(self.pc);
+        writer.bool(self.ime);
+        writer.u8(self.ie.into());
+        writer.u8(self.mode.into());
+        writer.u8(self.pass_in.0);
+        self.pass_in.1.save_state(writer);
+        self.memory.save_state(writer);
+        self.ppu.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.reg_file.load_state(reader)?;
+        self.addr_bus = reader.u16()?;
+        self.pc = reader.u16()?;
+        self.ime = reader.bool()?;
+        self.ie = reader.u8()?.into();
+        self.mode = Mode::try_from(reader.u8()?)?;
+        self.pass_in.0 = reader.u8()?;
+        self.pass_in.1.load_state(reader)?;
+        self.memory.load_state(reader)?;
+        self.ppu.load_state(reader)
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub enum Reg8 {
     A,
@@ -279,23 +363,19 @@ pub struct RegFile {
 
 pub struct Memory {
     pub boot_rom: [u8; 0x4000],
-    pub rom_bank0: [u8; 0x4000],       // 0x0000 - 0x3FFF
-    pub rom_bank1: [u8; 0x4000],       // 0x4000 - 0x7FFF
-    external_ram: [u8; 0x2000],   // 0xA000 - 0xBFFF
-    working_ram: [u8; 0x2000],     // 0xC000 - 0xDFFF
+    pub cartridge: Cartridge,          // 0x0000 - 0x7FFF, 0xA000 - 0xBFFF
+    working_ram: [u8; 0x8000],     // 0xC000 - 0xDFFF, 8 banks of 0x1000 on cgb
     echo_ram: [u8; 0x1E00],        // 0xE000 - 0xFDFF
     high_ram: [u8; 0x007F],        // 0xFF80 - 0xFFFE
-    use_boot: bool,
+    pub use_boot: bool,
 }
 
 impl Default for Memory {
     fn default() -> Self {
 	Memory {
 	    boot_rom: [0; 0x4000],
-	    rom_bank0: [0; 0x4000],
-	    rom_bank1: [0; 0x4000],
-	    external_ram: [0; 0x2000],
-	    working_ram: [0; 0x2000],
+	    cartridge: Cartridge::default(),
+	    working_ram: [0; 0x8000],
 	    echo_ram: [0; 0x1E00],
 	    high_ram: [0; 0x007F],
 	    use_boot: true,
@@ -303,6 +383,48 @@ impl Default for Memory {
     }
 }
 
+impl SaveState for Memory {
+    // The boot rom is reloaded from disk on startup, so only the flag selecting it is saved
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bytes(&self.working_ram);
+        writer.bytes(&self.echo_ram);
+        writer.bytes(&self.high_ram);
+        writer.bool(self.use_boot);
+        self.cartridge.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.bytes(&mut self.working_ram)?;
+        reader.bytes(&mut self.echo_ram)?;
+        reader.bytes(&mut self.high_ram)?;
+        self.use_boot = reader.bool()?;
+        self.cartridge.load_state(reader)
+    }
+}
+
+impl SaveState for RegFile {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bytes(&[self.A, self.B, self.C, self.D, self.E, self.H, self.L]);
+        writer.u16(self.SP);
+        writer.u8(self.flags.into());
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        let mut regs = [0; 7];
+        reader.bytes(&mut regs)?;
+        let [a, b, c, d, e, h, l] = regs;
+        self.A = a;
+        self.B = b;
+        self.C = c;
+        self.D = d;
+        self.E = e;
+        self.H = h;
+        self.L = l;
+        self.SP = reader.u16()?;
+        self.flags = reader.u8()?.into();
+        Ok(())
+    }
+}
 
 impl RegFile {
     pub fn set16(&mut self, reg: Reg16, value: u16) {
@@ -0,0 +1,331 @@
+use crate::audio::AudioOutput;
+use byteorder::{ByteOrder, NativeEndian};
+use rustboy::gameboy::Gameboy;
+use sdl2::event::Event;
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::Sdl;
+use sdl2::{
+    render::{Canvas, Texture, TextureCreator},
+    video::{Window, WindowContext},
+};
+use spin_sleep::LoopHelper;
+
+const SAVE_INTERVAL_FRAMES: u32 = 60 * 10;
+
+// Window, keyboard, audio device and frame pacing around the core
+pub struct Frontend {
+    pub gameboy: Gameboy,
+    pub mute: bool,
+    pub frame_limit: Option<u32>, // quit after this many frames
+    rewinding: bool,              // backspace held
+}
+
+impl<'a> Frontend {
+    pub fn new(gameboy: Gameboy) -> Frontend {
+        Frontend {
+            gameboy,
+            mute: false,
+            frame_limit: None,
+            rewinding: false,
+        }
+    }
+
+    pub fn run(
+        &mut self,
+        sdl_context: &Sdl,
+        texture_creator: &'a TextureCreator<WindowContext>,
+        canvas: &mut Canvas<Window>,
+    ) {
+        let mut loop_helper = LoopHelper::builder().report_interval_s(0.5).build_with_target_rate(59.7);
+        let mut event_pump = sdl_context.event_pump().unwrap();
+        let mut texture: Texture = texture_creator
+            .create_texture_streaming(PixelFormatEnum::ARGB8888, 160, 144)
+            .unwrap();
+	
+	let mut current_fps;
+	let mut rumble = false;
+	let mut frames_since_save = 0;
+	let mut frames = 0;
+	let mut audio = if self.mute {
+	    None
+	} else {
+	    AudioOutput::new(sdl_context)
+		.map_err(|err| println!("Audio disabled: {}", err))
+		.ok()
+	};
+        'running: loop {
+	    if Some(frames) == self.frame_limit {
+		break;
+	    }
+	    frames += 1;
+	    for event in event_pump.poll_iter() {
+		if self.handle_input(event) {
+		    break 'running;
+		}
+	    }
+	    
+	    self.gameboy.rewind_frame(self.rewinding);
+
+	    if let Some(fps) = loop_helper.report_rate() {
+		current_fps = Some(fps.round());
+		println!("current fps: {}", current_fps.unwrap());
+	    }
+
+            loop_helper.loop_start();
+	    self.gameboy.run_frame();
+	    let frame_buffer = self.gameboy.frame_buffer();
+	    texture
+                .with_lock(None, |buffer: &mut [u8], _pitch: usize| {
+                    for (i, pixel) in frame_buffer.iter().enumerate() {
+                        NativeEndian::write_u32(&mut buffer[i * 4..i * 4 + 4], *pixel);
+                    }
+                })
+                .unwrap();
+            canvas.copy(&texture, None, None).unwrap();
+            canvas.present();
+	    let samples = self.gameboy.cpu.ppu.io_registers.apu.take_samples();
+	    if let Some(audio) = audio.as_mut() {
+		audio.queue_samples(&samples);
+	    }
+	    self.gameboy.write_recordings(&samples);
+	    if self.gameboy.cpu.memory.cartridge.rumble_active() != rumble {
+		rumble = !rumble;
+		let title = if rumble { "Gameboy Window (rumble)" } else { "Gameboy Window" };
+		canvas.window_mut().set_title(title).unwrap();
+	    }
+	    frames_since_save += 1;
+	    if frames_since_save >= SAVE_INTERVAL_FRAMES && self.gameboy.cpu.memory.cartridge.ram_dirty {
+		frames_since_save = 0;
+		if let Err(err) = self.gameboy.write_save() {
+		    println!("Could not write save: {}", err);
+		}
+	    }
+            loop_helper.loop_sleep();
+        }
+    }
+
+    fn handle_input(&mut self, event: Event) -> bool {
+        match event {
+            Event::Quit { .. }
+            | Event::KeyDown {
+                keycode: Some(Keycode::Escape),
+                ..
+            } => return true,
+	    Event::KeyDown {
+		keycode: Some(Keycode::Down),
+                ..
+	    } => {
+		if self.gameboy.cpu.ppu.io_registers.joypad.select_direction == 0 {
+		    self.gameboy.cpu.ppu.io_registers.joypad.down_or_start = 1;
+		}
+	    }
+	    Event::KeyDown {
+		keycode: Some(Keycode::Up),
+                ..
+	    } => {
+		if self.gameboy.cpu.ppu.io_registers.joypad.select_direction == 0 {
+		    self.gameboy.cpu.ppu.io_registers.joypad.up_or_select = 1;
+		}
+	    }
+	    Event::KeyDown {
+		keycode: Some(Keycode::Left),
+                ..
+	    } => {
+		if self.gameboy.cpu.ppu.io_registers.joypad.select_direction == 0 {
+		    self.gameboy.cpu.ppu.io_registers.joypad.left_or_b = 1;
+		}
+	    }
+	    Event::KeyDown {
+		keycode: Some(Keycode::Right),
+                ..
+	    } => {
+		if self.gameboy.cpu.ppu.io_registers.joypad.select_direction == 0 {
+		    self.gameboy.cpu.ppu.io_registers.joypad.right_or_a = 0;
+		}
+	    }
+	    Event::KeyUp {
+		keycode: Some(Keycode::Down),
+                ..
+	    } => {
+		if self.gameboy.cpu.ppu.io_registers.joypad.select_direction == 0 {
+		    self.gameboy.cpu.ppu.io_registers.joypad.down_or_start = 0;
+		    self.gameboy.cpu.ppu.io_registers.interrupt_flag.joypad = 1;
+		    
+		}
+	    }
+	    Event::KeyUp {
+		keycode: Some(Keycode::Up),
+                ..
+	    } => {
+		if self.gameboy.cpu.ppu.io_registers.joypad.select_direction == 0 {
+		    self.gameboy.cpu.ppu.io_registers.joypad.up_or_select = 0;
+		    self.gameboy.cpu.ppu.io_registers.interrupt_flag.joypad = 1;
+		}
+	    }
+	    Event::KeyUp {
+		keycode: Some(Keycode::Left),
+                ..
+	    } => {
+		if self.gameboy.cpu.ppu.io_registers.joypad.select_direction == 0 {
+		    self.gameboy.cpu.ppu.io_registers.joypad.left_or_b = 0;
+		    self.gameboy.cpu.ppu.io_registers.interrupt_flag.joypad = 1;
+		}
+	    }
+	    Event::KeyUp {
+		keycode: Some(Keycode::Right),
+                ..
+	    } => {
+		if self.gameboy.cpu.ppu.io_registers.joypad.select_direction == 0 {
+		    self.gameboy.cpu.ppu.io_registers.joypad.right_or_a = 0;
+		    self.gameboy.cpu.ppu.io_registers.interrupt_flag.joypad = 1;
+		}
+	    }
+
+	    
+	    Event::KeyDown {
+		keycode: Some(Keycode::RShift),
+                ..
+	    } => {
+		if self.gameboy.cpu.ppu.io_registers.joypad.select_action == 0 {
+		    self.gameboy.cpu.ppu.io_registers.joypad.down_or_start = 1;
+		    self.gameboy.cpu.ppu.io_registers.interrupt_flag.joypad = 1;
+		}
+	    }
+	    Event::KeyDown {
+		keycode: Some(Keycode::Return),
+                ..
+	    } => {
+		if self.gameboy.cpu.ppu.io_registers.joypad.select_action == 0 {
+		    self.gameboy.cpu.ppu.io_registers.joypad.up_or_select = 1;
+		    self.gameboy.cpu.ppu.io_registers.interrupt_flag.joypad = 1;
+		}
+	    }
+	    Event::KeyDown {
+		keycode: Some(Keycode::A),
+                ..
+	    } => {
+		println!("a pressed!");
+		if self.gameboy.cpu.ppu.io_registers.joypad.select_action == 0 {
+		    self.gameboy.cpu.ppu.io_registers.joypad.left_or_b = 1;
+		    self.gameboy.cpu.ppu.io_registers.interrupt_flag.joypad = 1;
+		}
+	    }
+	    Event::KeyDown {
+		keycode: Some(Keycode::S),
+                ..
+	    } => {
+		if self.gameboy.cpu.ppu.io_registers.joypad.select_action == 0 {
+		    self.gameboy.cpu.ppu.io_registers.joypad.right_or_a = 0;
+		    self.gameboy.cpu.ppu.io_registers.interrupt_flag.joypad = 1;
+		}
+	    }
+	    Event::KeyUp {
+		keycode: Some(Keycode::RShift),
+                ..
+	    } => {
+		if self.gameboy.cpu.ppu.io_registers.joypad.select_action == 0 {
+		    self.gameboy.cpu.ppu.io_registers.joypad.down_or_start = 0;
+		    self.gameboy.cpu.ppu.io_registers.interrupt_flag.joypad = 1;
+		}
+	    }
+	    Event::KeyUp {
+		keycode: Some(Keycode::Return),
+                ..
+	    } => {
+		if self.gameboy.cpu.ppu.io_registers.joypad.select_action == 0 {
+		    self.gameboy.cpu.ppu.io_registers.joypad.up_or_select = 0;
+		    self.gameboy.cpu.ppu.io_registers.interrupt_flag.joypad = 1;
+		}
+	    }
+	    Event::KeyUp {
+		keycode: Some(Keycode::A),
+                ..
+	    } => {
+		if self.gameboy.cpu.ppu.io_registers.joypad.select_action == 0 {
+		    self.gameboy.cpu.ppu.io_registers.joypad.left_or_b = 0;
+		    self.gameboy.cpu.ppu.io_registers.interrupt_flag.joypad = 1;
+		}
+	    }
+	    Event::KeyUp {
+		keycode: Some(Keycode::S),
+                ..
+	    } => {
+		if self.gameboy.cpu.ppu.io_registers.joypad.select_action == 0 {
+		    self.gameboy.cpu.ppu.io_registers.joypad.right_or_a = 0;
+		    self.gameboy.cpu.ppu.io_registers.interrupt_flag.joypad = 1;
+		}
+	    }
+	    Event::KeyDown {
+		keycode: Some(Keycode::Backspace),
+                ..
+	    } => self.rewinding = true,
+	    Event::KeyUp {
+		keycode: Some(Keycode::Backspace),
+                ..
+	    } => self.rewinding = false,
+	    Event::KeyDown {
+		keycode: Some(Keycode::F12),
+		keymod,
+		repeat: false,
+                ..
+	    } => {
+		if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+		    self.gameboy.toggle_channel_recording();
+		} else {
+		    self.gameboy.toggle_recording();
+		}
+	    }
+	    Event::KeyDown {
+		keycode: Some(keycode),
+		keymod,
+		repeat: false,
+		..
+	    } if Frontend::apu_channel(keycode).is_some() => {
+		let channel = Frontend::apu_channel(keycode).unwrap();
+		self.gameboy.toggle_channel(channel, keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD));
+	    }
+	    Event::KeyDown {
+		keycode: Some(keycode),
+		keymod,
+		repeat: false,
+		..
+	    } if Frontend::state_slot(keycode).is_some() => {
+		let slot = Frontend::state_slot(keycode).unwrap();
+		if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+		    self.gameboy.save_state_slot(slot);
+		} else {
+		    self.gameboy.load_state_slot(slot);
+		}
+	    }
+            _ => {},
+        }
+	return false;
+    }
+
+    fn apu_channel(keycode: Keycode) -> Option<usize> {
+	match keycode {
+	    Keycode::Num1 => Some(0),
+	    Keycode::Num2 => Some(1),
+	    Keycode::Num3 => Some(2),
+	    Keycode::Num4 => Some(3),
+	    _ => None,
+	}
+    }
+
+    // F1 - F9 load the matching save state slot, shift + F1 - F9 save to it
+    fn state_slot(keycode: Keycode) -> Option<u8> {
+	match keycode {
+	    Keycode::F1 => Some(1),
+	    Keycode::F2 => Some(2),
+	    Keycode::F3 => Some(3),
+	    Keycode::F4 => Some(4),
+	    Keycode::F5 => Some(5),
+	    Keycode::F6 => Some(6),
+	    Keycode::F7 => Some(7),
+	    Keycode::F8 => Some(8),
+	    Keycode::F9 => Some(9),
+	    _ => None,
+	}
+    }
+}
@@ -1,337 +1,505 @@
+use crate::apu::SAMPLE_RATE;
+use crate::cartridge::{Cartridge, CartridgeError, CartridgeHeader, CgbFlag};
 use crate::cpu::CPU;
-use crate::ppu::{GameboyColor, Mode, PPU};
-use byteorder::{ByteOrder, NativeEndian};
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use sdl2::pixels::PixelFormatEnum;
-use sdl2::Sdl;
-use sdl2::{
-    render::{Canvas, Texture, TextureCreator},
-    video::{Window, WindowContext},
-};
-use spin_sleep::LoopHelper;
-use std::fs;
+use crate::ppu::{rgb555_to_argb, GameboyColor, Palette, Pixel, PPU};
+use crate::rewind::RewindBuffer;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter, STATE_MAGIC, STATE_VERSION};
+use crate::screenshot::write_bmp;
+use crate::sgb::Mask;
+use crate::wav::WavWriter;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
 
 const CPU_CYCLES_PER_FRAME: u32 = 69833;
-const MODE2_CYCLES: u16 = 80 / 4;
-const MODE3_CYCLES: u16 = 172 / 4;
-const MODE0_CYCLES: u16 = 204 / 4;
-const MODE1_CYCLES: u16 = 456 / 4;
+const FRAME_CYCLES: u32 = 70224 / 4;
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
 const MODE_OFF_CYCLES: u16 = 456 / 4;
+const REWIND_INTERVAL_FRAMES: u32 = 4;
+const REWIND_MEMORY_CAP: usize = 64 * 1024 * 1024;
+
+// Values the boot rom leaves in the io registers, sound is powered on first so the
+// channel registers take effect. The joypad is set apart so the sgb sees no packet
+const POST_BOOT_IO: [(usize, u8); 31] = [
+    (0xFF26, 0xF1), (0xFF01, 0x00), (0xFF02, 0x7E), (0xFF05, 0x00),
+    (0xFF06, 0x00), (0xFF07, 0xF8), (0xFF0F, 0xE1), (0xFF10, 0x80), (0xFF11, 0xBF),
+    (0xFF12, 0xF3), (0xFF13, 0xFF), (0xFF14, 0xBF), (0xFF16, 0x3F), (0xFF17, 0x00),
+    (0xFF18, 0xFF), (0xFF19, 0xBF), (0xFF1A, 0x7F), (0xFF1B, 0xFF), (0xFF1C, 0x9F),
+    (0xFF1D, 0xFF), (0xFF1E, 0xBF), (0xFF20, 0xFF), (0xFF21, 0x00), (0xFF22, 0x00),
+    (0xFF23, 0xBF), (0xFF24, 0x77), (0xFF25, 0xF3), (0xFF40, 0x91), (0xFF41, 0x85),
+    (0xFF46, 0xFF), (0xFF47, 0xFC),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Model {
+    #[default]
+    Dmg,
+    Mgb, // Game Boy Pocket
+    Sgb, // Super Game Boy, a dmg that also answers command packets
+    Cgb,
+}
+
+impl Model {
+    pub const NAMES: [&'static str; 4] = ["dmg", "mgb", "sgb", "cgb"];
 
+    pub fn from_name(name: &str) -> Option<Model> {
+        match name {
+            "dmg" => Some(Model::Dmg),
+            "mgb" => Some(Model::Mgb),
+            "sgb" => Some(Model::Sgb),
+            "cgb" => Some(Model::Cgb),
+            _ => None,
+        }
+    }
+
+    // The model a cartridge asks for when none is forced
+    pub fn for_cartridge(header: &CartridgeHeader) -> Model {
+        match header.cgb_flag {
+            CgbFlag::Dmg if header.supports_sgb() => Model::Sgb,
+            CgbFlag::Dmg => Model::Dmg,
+            CgbFlag::CgbEnhanced | CgbFlag::CgbOnly => Model::Cgb,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(PathBuf, io::Error),
+    BootRomSize(usize),
+    Cartridge(CartridgeError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(path, err) => write!(f, "could not read {}: {}", path.display(), err),
+            LoadError::BootRomSize(len) => write!(f, "boot rom has an invalid size of {} bytes", len),
+            LoadError::Cartridge(err) => write!(f, "invalid cartridge: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
 
 pub struct Gameboy {
     pub cpu: CPU,
-    mode: Mode,
+    rom_path: Option<PathBuf>,
+    save_path: Option<PathBuf>, // battery backed ram is persisted here
+    rewind: RewindBuffer,
+    recorder: Option<WavWriter<BufWriter<File>>>, // mixed apu output while recording
+    channel_recorders: Vec<WavWriter<BufWriter<File>>>, // one per apu channel, empty when off
+    frame_buffer: Vec<u32>,
+    pub palette: Palette,
+    model: Model,
 }
 
-impl<'a> Gameboy {
+impl Gameboy {
     pub fn new() -> Gameboy {
         let ppu = PPU::new(Default::default());
         let cpu = CPU::new(ppu);
         Gameboy {
             cpu,
-            mode: Mode::Off,
+            rom_path: None,
+            save_path: None,
+            rewind: RewindBuffer::new(REWIND_INTERVAL_FRAMES, REWIND_MEMORY_CAP),
+            recorder: None,
+            channel_recorders: Vec::new(),
+            frame_buffer: vec![Palette::default().color(GameboyColor::White); SCREEN_WIDTH * SCREEN_HEIGHT],
+            palette: Palette::default(),
+            model: Model::default(),
         }
     }
-    pub fn init_gameboy(&mut self) {
-        println!("Starting up!");
-        let binary = &fs::read("bootix_dmg.bin").unwrap();
-        println!("Boot Rom Length: {}", binary.len());
-	let path = std::env::args().nth(1).expect("First argument must be binary");
-	let cartridge_binary = &fs::read(path).expect("Must provide binary!");
-        let mut i = 0;
-        for el in binary {
-            self.cpu.memory.boot_rom[i] = el.clone();
-            i = i + 1;
-        }
-	println!("Cartridge Rom Length: 0x{:x}", cartridge_binary.len());
-	for (i, el) in cartridge_binary.iter().enumerate() {
-	    if i < 0x4000 {
-		self.cpu.memory.rom_bank0[i] = el.clone();
-	    } else {
-		self.cpu.memory.rom_bank1[i - 0x4000] = el.clone();
-	    }
+    pub fn load_boot_rom(&mut self, path: &Path) -> Result<(), LoadError> {
+	let binary = fs::read(path).map_err(|err| LoadError::Io(path.to_path_buf(), err))?;
+	let boot_rom = &mut self.cpu.memory.boot_rom;
+	if binary.is_empty() || binary.len() > boot_rom.len() {
+	    return Err(LoadError::BootRomSize(binary.len()));
 	}
+	boot_rom[..binary.len()].copy_from_slice(&binary);
+	Ok(())
     }
 
-    pub fn step_gameboy(&mut self) {
-	self.cpu.step(false);
-    }
-    
-    pub fn run_without_graphics(&mut self) {
-	loop {
-	    println!("{}", self.cpu.pc);
-            self.cpu.step(false);
-        }
+    // Also picks up <rom>.sav when the cartridge has a battery
+    pub fn load_rom(&mut self, path: &Path) -> Result<(), LoadError> {
+	let cartridge_binary = fs::read(path).map_err(|err| LoadError::Io(path.to_path_buf(), err))?;
+	let cartridge = Cartridge::new(cartridge_binary).map_err(LoadError::Cartridge)?;
+	println!("Cartridge: {} ({:?})", cartridge.header.title, cartridge.header.cartridge_type.mapper);
+	let header = &cartridge.header;
+	if header.header_checksum != header.computed_header_checksum {
+	    println!(
+		"Warning: header checksum is {:#x} but should be {:#x}, a dmg boot rom would lock up",
+		header.header_checksum, header.computed_header_checksum
+	    );
+	}
+	self.cpu.memory.cartridge = cartridge;
+	if self.cpu.memory.cartridge.has_battery() {
+	    let save_path = path.with_extension("sav");
+	    if let Ok(save) = fs::read(&save_path) {
+		println!("Loading save from {}", save_path.display());
+		self.cpu.memory.cartridge.load_save_data(&save);
+	    }
+	    self.save_path = Some(save_path);
+	}
+	self.rom_path = Some(path.to_path_buf());
+	self.update_color_modes();
+	Ok(())
     }
 
-    pub fn run_emulator(
-        &mut self,
-        sdl_context: &Sdl,
-        texture_creator: &'a TextureCreator<WindowContext>,
-        canvas: &mut Canvas<Window>,
-    ) {
-        let mut loop_helper = LoopHelper::builder().report_interval_s(0.5).build_with_target_rate(59.7);
-        let mut event_pump = sdl_context.event_pump().unwrap();
-        let mut texture: Texture = texture_creator
-            .create_texture_streaming(PixelFormatEnum::ARGB8888, 160, 144)
-            .unwrap();
-	
-	let mut frame_buffer: [u8; 92160] = [0; 92160];
-	let mut current_fps;
-        'running: loop {
-	    for event in event_pump.poll_iter() {
-		if self.handle_input(event) {
-		    break 'running;
-		}
-	    }
-	    
-	    if let Some(fps) = loop_helper.report_rate() {
-		current_fps = Some(fps.round());
-		println!("current fps: {}", current_fps.unwrap());
-	    }
+    pub fn model(&self) -> Model {
+	self.model
+    }
 
-            while self.cpu.ppu.io_registers.lcdc.lcd_ppu_enable == 0 {
-                self.cpu.step(false);
-            }
-            //println!("LCD on!");
-
-            loop_helper.loop_start();
-            canvas.clear();
-            for scanline in 0..154 {
-                self.cpu.ppu.io_registers.ly = scanline;
-		if self.cpu.ppu.io_registers.ly == self.cpu.ppu.io_registers.lyc {
-                    self.cpu.ppu.io_registers.lcd_status.lyc_eq_ly = 1;
-		    if self.cpu.ppu.io_registers.lcd_status.lyc_ly_stat_interrupt == 1 {
-			self.cpu.ppu.io_registers.interrupt_flag.lcd_stat = 1;
-		    }
-                } else {
-                    self.cpu.ppu.io_registers.lcd_status.lyc_eq_ly = 0;
-                };
-                
-                if scanline < 144 {
-                    let oam_entries = self.cpu.ppu.object_search();
-		    self.cpu.ppu.io_registers.lcd_status.mode = 2;
-		    if self.cpu.ppu.io_registers.lcd_status.mode_two_stat_interrupt == 1 {
-			self.cpu.ppu.io_registers.interrupt_flag.lcd_stat = 1;
-		    }
-                    for _i in 0..MODE2_CYCLES {
-                        self.cpu.step(false);
-                    }
-
-                    let color_line = self.cpu.ppu.draw(oam_entries);
-		    self.cpu.ppu.io_registers.lcd_status.mode = 3;
-                    for _i in 0..MODE3_CYCLES {
-                        self.cpu.step(false);
-                    }
-                    Gameboy::write_line_to_frame_buffer(&mut frame_buffer, color_line, scanline);
-		    self.cpu.ppu.io_registers.lcd_status.mode = 0;
-		    if self.cpu.ppu.io_registers.lcd_status.mode_zero_stat_interrupt == 1 {
-			self.cpu.ppu.io_registers.interrupt_flag.lcd_stat = 1;
-		    }
-                    for _i in 0..MODE0_CYCLES {
-                        self.cpu.step(false);
-                    }
-                } else {
-		    if scanline == 144 {
-			self.cpu.ppu.io_registers.interrupt_flag.vblank = 1;
-			if self.cpu.ppu.io_registers.lcd_status.mode_one_stat_interrupt == 1 {
-			    self.cpu.ppu.io_registers.interrupt_flag.lcd_stat = 1;
-			}
-		    }
-		    self.cpu.ppu.io_registers.lcd_status.mode = 1;
-                    for _i in 0..MODE1_CYCLES {
-                        self.cpu.step(false);
-			if self.cpu.ppu.io_registers.lcdc.lcd_ppu_enable == 0 {
-			    loop_helper.loop_sleep();
-			    canvas.set_draw_color(GameboyColor::White);
-			    canvas.clear();
-			    self.cpu.ppu.io_registers.ly = 0;
-			    self.cpu.ppu.io_registers.lcd_status.mode = 0;
-			    continue 'running;
-			}
-                    }
-                }
-            }
-	    texture
-                .with_lock(None, |buffer: &mut [u8], _pitch: usize| {
-                    for (i, el) in frame_buffer.iter().enumerate() {
-                        buffer[i] = *el;
-                    }
-                })
-                .unwrap();
-            canvas.copy(&texture, None, None).unwrap();
-            canvas.present();
-            loop_helper.loop_sleep();
-        }
+    pub fn set_model(&mut self, model: Model) {
+	self.model = model;
+	self.update_color_modes();
     }
 
-    pub fn write_line_to_frame_buffer(
-        frame_buffer: &mut [u8; 92160],
-        color_line: Vec<GameboyColor>,
-        scanline: u8,
-    ) {
-        let color_line: Vec<u32> = color_line.iter().map(|color| color.into()).collect();
-        for (index, color) in color_line.iter().enumerate() {
-	    let begin_index = scanline as usize * 160 * 4 + 4 * index;
-            NativeEndian::write_u32(&mut frame_buffer[begin_index .. begin_index + 4], *color);
-        }
+    // Cgb hardware only runs in color mode for cartridges that support it, the same
+    // goes for the super game boy and its command packets
+    fn update_color_modes(&mut self) {
+	let header = &self.cpu.memory.cartridge.header;
+	let cgb_mode = self.model == Model::Cgb && header.cgb_flag != CgbFlag::Dmg;
+	let sgb = self.model == Model::Sgb && header.supports_sgb();
+	self.cpu.ppu.io_registers.cgb_mode = cgb_mode;
+	self.cpu.ppu.io_registers.sgb.enabled = sgb;
     }
 
-    fn handle_input(&mut self, event: Event) -> bool {
-        match event {
-            Event::Quit { .. }
-            | Event::KeyDown {
-                keycode: Some(Keycode::Escape),
-                ..
-            } => return true,
-	    Event::KeyDown {
-		keycode: Some(Keycode::Down),
-                ..
-	    } => {
-		if self.cpu.ppu.io_registers.joypad.select_direction == 0 {
-		    self.cpu.ppu.io_registers.joypad.down_or_start = 1;
-		}
+    // Starts at the cartridge entry point with the state the boot rom of self.model
+    // leaves behind, so no boot rom file is needed
+    pub fn skip_boot(&mut self) {
+	let header = &self.cpu.memory.cartridge.header;
+	let header_checksum = header.header_checksum;
+	let cgb_cartridge = header.cgb_flag != CgbFlag::Dmg;
+	let reg_file = &mut self.cpu.reg_file;
+	match self.model {
+	    Model::Dmg | Model::Mgb => {
+		reg_file.A = if self.model == Model::Dmg { 0x01 } else { 0xFF };
+		// the header check leaves half carry and carry set unless the checksum is 0
+		reg_file.flags = if header_checksum == 0 { 0x80 } else { 0xB0 }.into();
+		reg_file.B = 0x00;
+		reg_file.C = 0x13;
+		reg_file.D = 0x00;
+		reg_file.E = 0xD8;
+		reg_file.H = 0x01;
+		reg_file.L = 0x4D;
 	    }
-	    Event::KeyDown {
-		keycode: Some(Keycode::Up),
-                ..
-	    } => {
-		if self.cpu.ppu.io_registers.joypad.select_direction == 0 {
-		    self.cpu.ppu.io_registers.joypad.up_or_select = 1;
-		}
-	    }
-	    Event::KeyDown {
-		keycode: Some(Keycode::Left),
-                ..
-	    } => {
-		if self.cpu.ppu.io_registers.joypad.select_direction == 0 {
-		    self.cpu.ppu.io_registers.joypad.left_or_b = 1;
-		}
-	    }
-	    Event::KeyDown {
-		keycode: Some(Keycode::Right),
-                ..
-	    } => {
-		if self.cpu.ppu.io_registers.joypad.select_direction == 0 {
-		    self.cpu.ppu.io_registers.joypad.right_or_a = 0;
-		}
+	    Model::Sgb => {
+		reg_file.A = 0x01;
+		reg_file.flags = 0x00.into();
+		reg_file.B = 0x00;
+		reg_file.C = 0x14;
+		reg_file.D = 0x00;
+		reg_file.E = 0x00;
+		reg_file.H = 0xC0;
+		reg_file.L = 0x60;
 	    }
-	    Event::KeyUp {
-		keycode: Some(Keycode::Down),
-                ..
-	    } => {
-		if self.cpu.ppu.io_registers.joypad.select_direction == 0 {
-		    self.cpu.ppu.io_registers.joypad.down_or_start = 0;
-		    self.cpu.ppu.io_registers.interrupt_flag.joypad = 1;
-		    
+	    Model::Cgb => {
+		reg_file.A = 0x11;
+		reg_file.flags = 0x80.into();
+		reg_file.B = 0x00;
+		reg_file.C = 0x00;
+		if cgb_cartridge {
+		    reg_file.D = 0xFF;
+		    reg_file.E = 0x56;
+		    reg_file.H = 0x00;
+		    reg_file.L = 0x0D;
+		} else {
+		    reg_file.D = 0x00;
+		    reg_file.E = 0x08;
+		    reg_file.H = 0x00;
+		    reg_file.L = 0x7C;
 		}
 	    }
-	    Event::KeyUp {
-		keycode: Some(Keycode::Up),
-                ..
-	    } => {
-		if self.cpu.ppu.io_registers.joypad.select_direction == 0 {
-		    self.cpu.ppu.io_registers.joypad.up_or_select = 0;
-		    self.cpu.ppu.io_registers.interrupt_flag.joypad = 1;
-		}
-	    }
-	    Event::KeyUp {
-		keycode: Some(Keycode::Left),
-                ..
-	    } => {
-		if self.cpu.ppu.io_registers.joypad.select_direction == 0 {
-		    self.cpu.ppu.io_registers.joypad.left_or_b = 0;
-		    self.cpu.ppu.io_registers.interrupt_flag.joypad = 1;
-		}
+	}
+	reg_file.SP = 0xFFFE;
+
+	let io_registers = &mut self.cpu.ppu.io_registers;
+	for &(addr, value) in POST_BOOT_IO.iter() {
+	    io_registers.set(addr, value);
+	}
+	io_registers.joypad = 0xCF.into();
+	match self.model {
+	    Model::Dmg | Model::Mgb | Model::Sgb => io_registers.timer.set_div_counter(0xABCC),
+	    Model::Cgb => {
+		io_registers.set(0xFF02, 0x7F);
+		// the cgb boot time depends on the logo animation, this is a typical value
+		io_registers.timer.set_div_counter(0x1EA0);
 	    }
-	    Event::KeyUp {
-		keycode: Some(Keycode::Right),
-                ..
-	    } => {
-		if self.cpu.ppu.io_registers.joypad.select_direction == 0 {
-		    self.cpu.ppu.io_registers.joypad.right_or_a = 0;
-		    self.cpu.ppu.io_registers.interrupt_flag.joypad = 1;
-		}
+	}
+	io_registers.set(0xFF50, 0x01);
+	self.cpu.ie = 0x00.into();
+	self.cpu.memory.use_boot = false;
+	self.cpu.pc = 0x100;
+    }
+
+    // Flushes everything that outlives the session: battery ram and open recordings
+    pub fn shutdown(&mut self) {
+	if let Err(err) = self.write_save() {
+	    println!("Could not write save: {}", err);
+	}
+	self.stop_recording();
+	self.stop_channel_recording();
+    }
+
+    pub fn save_screenshot(&self, path: &Path) -> io::Result<()> {
+	let mut out = BufWriter::new(File::create(path)?);
+	write_bmp(&mut out, SCREEN_WIDTH, SCREEN_HEIGHT, &self.frame_buffer)?;
+	out.flush()
+    }
+
+    // First free <rom>-N<suffix>.wav next to the rom
+    fn recording_path(&self, suffix: &str) -> Option<PathBuf> {
+	let rom_path = self.rom_path.as_ref()?;
+	let stem = rom_path.file_stem()?.to_string_lossy();
+	(1..)
+	    .map(|n| rom_path.with_file_name(format!("{}-{}{}.wav", stem, n, suffix)))
+	    .find(|path| !path.exists())
+    }
+
+    pub fn start_recording(&mut self, path: PathBuf) {
+	match WavWriter::create(&path, SAMPLE_RATE, 2) {
+	    Ok(recorder) => {
+		println!("Recording audio to {}", path.display());
+		self.recorder = Some(recorder);
 	    }
+	    Err(err) => println!("Could not create {}: {}", path.display(), err),
+	}
+    }
 
-	    
-	    Event::KeyDown {
-		keycode: Some(Keycode::RShift),
-                ..
-	    } => {
-		if self.cpu.ppu.io_registers.joypad.select_action == 0 {
-		    self.cpu.ppu.io_registers.joypad.down_or_start = 1;
-		    self.cpu.ppu.io_registers.interrupt_flag.joypad = 1;
-		}
+    pub fn stop_recording(&mut self) {
+	if let Some(recorder) = self.recorder.take() {
+	    match recorder.finish() {
+		Ok(_) => println!("Stopped recording audio"),
+		Err(err) => println!("Could not finish recording: {}", err),
 	    }
-	    Event::KeyDown {
-		keycode: Some(Keycode::Return),
-                ..
-	    } => {
-		if self.cpu.ppu.io_registers.joypad.select_action == 0 {
-		    self.cpu.ppu.io_registers.joypad.up_or_select = 1;
-		    self.cpu.ppu.io_registers.interrupt_flag.joypad = 1;
+	}
+    }
+
+    pub fn toggle_recording(&mut self) {
+	if self.recorder.is_some() {
+	    self.stop_recording();
+	} else if let Some(path) = self.recording_path("") {
+	    self.start_recording(path);
+	}
+    }
+
+    // Each channel goes to <rom>-N-chK.wav, mono and before panning
+    pub fn toggle_channel_recording(&mut self) {
+	if !self.channel_recorders.is_empty() {
+	    self.stop_channel_recording();
+	    return;
+	}
+	let base = match self.recording_path("-ch1") {
+	    Some(path) => path,
+	    None => return,
+	};
+	let base = base.to_string_lossy();
+	for channel in 1..=4 {
+	    let path = PathBuf::from(base.replace("-ch1.wav", &format!("-ch{}.wav", channel)));
+	    match WavWriter::create(&path, SAMPLE_RATE, 1) {
+		Ok(recorder) => self.channel_recorders.push(recorder),
+		Err(err) => {
+		    println!("Could not create {}: {}", path.display(), err);
+		    self.channel_recorders.clear();
+		    return;
 		}
 	    }
-	    Event::KeyDown {
-		keycode: Some(Keycode::A),
-                ..
-	    } => {
-		println!("a pressed!");
-		if self.cpu.ppu.io_registers.joypad.select_action == 0 {
-		    self.cpu.ppu.io_registers.joypad.left_or_b = 1;
-		    self.cpu.ppu.io_registers.interrupt_flag.joypad = 1;
-		}
+	}
+	self.cpu.ppu.io_registers.apu.set_channel_capture(true);
+	println!("Recording apu channels to {}", base);
+    }
+
+    pub fn stop_channel_recording(&mut self) {
+	self.cpu.ppu.io_registers.apu.set_channel_capture(false);
+	for recorder in self.channel_recorders.drain(..) {
+	    if let Err(err) = recorder.finish() {
+		println!("Could not finish recording: {}", err);
 	    }
-	    Event::KeyDown {
-		keycode: Some(Keycode::S),
-                ..
-	    } => {
-		if self.cpu.ppu.io_registers.joypad.select_action == 0 {
-		    self.cpu.ppu.io_registers.joypad.right_or_a = 0;
-		    self.cpu.ppu.io_registers.interrupt_flag.joypad = 1;
-		}
+	}
+    }
+
+    pub fn write_recordings(&mut self, samples: &[f32]) {
+	if let Some(recorder) = self.recorder.as_mut() {
+	    if let Err(err) = recorder.write_samples(samples) {
+		println!("Could not write recording: {}", err);
+		self.recorder = None;
 	    }
-	    Event::KeyUp {
-		keycode: Some(Keycode::RShift),
-                ..
-	    } => {
-		if self.cpu.ppu.io_registers.joypad.select_action == 0 {
-		    self.cpu.ppu.io_registers.joypad.down_or_start = 0;
-		    self.cpu.ppu.io_registers.interrupt_flag.joypad = 1;
+	}
+	if !self.channel_recorders.is_empty() {
+	    let channel_samples = self.cpu.ppu.io_registers.apu.take_channel_samples();
+	    for (recorder, samples) in self.channel_recorders.iter_mut().zip(channel_samples.iter()) {
+		if let Err(err) = recorder.write_samples(samples) {
+		    println!("Could not write recording: {}", err);
 		}
 	    }
-	    Event::KeyUp {
-		keycode: Some(Keycode::Return),
-                ..
-	    } => {
-		if self.cpu.ppu.io_registers.joypad.select_action == 0 {
-		    self.cpu.ppu.io_registers.joypad.up_or_select = 0;
-		    self.cpu.ppu.io_registers.interrupt_flag.joypad = 1;
-		}
+	}
+    }
+
+    // 1 - 4 mute an apu channel, shift + 1 - 4 solo it or end the solo
+    pub fn toggle_channel(&mut self, channel: usize, solo: bool) {
+	let apu = &mut self.cpu.ppu.io_registers.apu;
+	if solo {
+	    let solo = if apu.solo() == Some(channel) { None } else { Some(channel) };
+	    apu.set_solo(solo);
+	} else {
+	    apu.set_muted(channel, !apu.is_muted(channel));
+	}
+	let status: Vec<String> = (0..4)
+	    .map(|channel| format!("ch{}: {}", channel + 1, if apu.channel_audible(channel) { "on" } else { "off" }))
+	    .collect();
+	println!("{}", status.join(", "));
+    }
+
+    pub fn write_save(&mut self) -> io::Result<()> {
+	if let Some(save_path) = &self.save_path {
+	    fs::write(save_path, self.cpu.memory.cartridge.save_data())?;
+	    self.cpu.memory.cartridge.ram_dirty = false;
+	}
+	Ok(())
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+	let header = &self.cpu.memory.cartridge.header;
+	let mut writer = StateWriter::new();
+	writer.bytes(STATE_MAGIC);
+	writer.u32(STATE_VERSION);
+	writer.u16(header.global_checksum);
+	writer.u8(header.header_checksum);
+	self.cpu.save_state(&mut writer);
+	writer.into_bytes()
+    }
+
+    // On failure the machine is left exactly as it was before the call
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+	let backup = self.save_state();
+	let result = self.read_state(state);
+	if result.is_err() {
+	    self.read_state(&backup).expect("restoring backup state failed");
+	}
+	result
+    }
+
+    fn read_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+	let mut reader = StateReader::new(state);
+	let mut magic = [0; 4];
+	reader.bytes(&mut magic)?;
+	if &magic != STATE_MAGIC {
+	    return Err(StateError::BadMagic);
+	}
+	let version = reader.u32()?;
+	if version != STATE_VERSION {
+	    return Err(StateError::UnsupportedVersion(version));
+	}
+	let header = &self.cpu.memory.cartridge.header;
+	if reader.u16()? != header.global_checksum || reader.u8()? != header.header_checksum {
+	    return Err(StateError::RomMismatch);
+	}
+	self.cpu.load_state(&mut reader)
+    }
+
+    fn state_path(&self, slot: u8) -> Option<PathBuf> {
+	self.rom_path.as_ref().map(|path| path.with_extension(format!("ss{}", slot)))
+    }
+
+    pub fn save_state_slot(&self, slot: u8) {
+	if let Some(path) = self.state_path(slot) {
+	    match fs::write(&path, self.save_state()) {
+		Ok(()) => println!("Saved state to slot {}", slot),
+		Err(err) => println!("Could not write {}: {}", path.display(), err),
 	    }
-	    Event::KeyUp {
-		keycode: Some(Keycode::A),
-                ..
-	    } => {
-		if self.cpu.ppu.io_registers.joypad.select_action == 0 {
-		    self.cpu.ppu.io_registers.joypad.left_or_b = 0;
-		    self.cpu.ppu.io_registers.interrupt_flag.joypad = 1;
+	}
+    }
+
+    pub fn load_state_slot(&mut self, slot: u8) {
+	if let Some(path) = self.state_path(slot) {
+	    match fs::read(&path).map(|state| self.load_state(&state)) {
+		Ok(Ok(())) => {
+		    self.rewind.clear();
+		    println!("Loaded state from slot {}", slot);
 		}
+		Ok(Err(err)) => println!("Could not load slot {}: {}", slot, err),
+		Err(err) => println!("Could not read {}: {}", path.display(), err),
 	    }
-	    Event::KeyUp {
-		keycode: Some(Keycode::S),
-                ..
-	    } => {
-		if self.cpu.ppu.io_registers.joypad.select_action == 0 {
-		    self.cpu.ppu.io_registers.joypad.right_or_a = 0;
-		    self.cpu.ppu.io_registers.interrupt_flag.joypad = 1;
+	}
+    }
+
+    // Called once per frame: steps back one snapshot while rewinding, otherwise takes
+    // a new snapshot every REWIND_INTERVAL_FRAMES
+    pub fn rewind_frame(&mut self, rewinding: bool) {
+	if rewinding {
+	    if let Some(state) = self.rewind.pop() {
+		// older snapshots are rebuilt from this one, so they go with it
+		if let Err(err) = self.load_state(&state) {
+		    println!("Could not rewind: {}", err);
+		    self.rewind.clear();
 		}
 	    }
-            _ => {},
+	} else if self.rewind.frame_tick() {
+	    self.rewind.push(self.save_state());
+	}
+    }
+
+    pub fn step_gameboy(&mut self) {
+	self.cpu.step(false);
+    }
+    
+    pub fn run_without_graphics(&mut self) {
+	loop {
+	    self.run_frame();
         }
-	return false;
+    }
+
+    // ARGB8888 pixels, row by row
+    pub fn frame_buffer(&self) -> &[u32] {
+	&self.frame_buffer
+    }
+
+    // Runs the machine for exactly one video frame, until the ppu wraps around to line 0.
+    // While the lcd is off a frame's worth of cycles passes and the screen is blank
+    pub fn run_frame(&mut self) {
+	self.cpu.ppu.frame_ready = false;
+	let mut cycles = 0;
+	while !self.cpu.ppu.frame_ready {
+	    self.step_machine_cycle();
+	    cycles += 1;
+	    if cycles >= FRAME_CYCLES && self.cpu.ppu.io_registers.lcdc.lcd_ppu_enable == 0 {
+		self.blank_frame();
+		return;
+	    }
+	}
+	self.update_frame_buffer();
+    }
+
+    // One machine cycle of the ppu and apu, the cpu gets two of them in double speed
+    fn step_machine_cycle(&mut self) {
+	self.cpu.step(false);
+	if self.cpu.ppu.io_registers.key1.double_speed {
+	    self.cpu.step(false);
+	}
+    }
+
+    fn blank_frame(&mut self) {
+	let white = self.palette.color(GameboyColor::White);
+	self.frame_buffer.iter_mut().for_each(|pixel| *pixel = white);
+    }
+
+    // Cgb games bring their own colors, sgb games color the shades through their
+    // attribute map and everything else goes through the shade palette
+    fn update_frame_buffer(&mut self) {
+	let sgb = &self.cpu.ppu.io_registers.sgb;
+	if sgb.enabled && sgb.mask == Mask::Freeze {
+	    return;
+	}
+	let palette = self.palette;
+	for (i, (pixel, color)) in self.frame_buffer.iter_mut().zip(self.cpu.ppu.screen.iter()).enumerate() {
+	    *pixel = match *color {
+		Pixel::Color(color) => rgb555_to_argb(color),
+		Pixel::Shade(shade) if sgb.enabled => sgb.color(i % SCREEN_WIDTH, i / SCREEN_WIDTH, shade),
+		Pixel::Shade(shade) => palette.color(shade),
+	    };
+	}
     }
 }
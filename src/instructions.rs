@@ -1,4 +1,5 @@
 use crate::cpu::{Flags, Reg16, Reg8, RegFile, CPU};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
 #[derive(Clone, Copy, Default, Debug)]
 pub struct StagePassThrough {
@@ -11,6 +12,29 @@ pub struct StagePassThrough {
     pub instruction_stage: u8,
 }
 
+impl SaveState for StagePassThrough {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.data);
+        writer.u16(self.data16);
+        writer.u8(self.cb_op);
+        writer.u8(self.flags.into());
+        writer.bool(self.ei);
+        writer.bool(self.di);
+        writer.u8(self.instruction_stage);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.data = reader.u8()?;
+        self.data16 = reader.u16()?;
+        self.cb_op = reader.u8()?;
+        self.flags = reader.u8()?.into();
+        self.ei = reader.bool()?;
+        self.di = reader.bool()?;
+        self.instruction_stage = reader.u8()?;
+        Ok(())
+    }
+}
+
 pub enum CC {
     UC, //not a real condition code, but stands for unconditional
     NZ,
@@ -109,6 +133,7 @@ pub fn instruction_decode(opcode: u8, cpu: &mut CPU, passed: StagePassThrough) -
 	    }
 	}
 	0x76 => StagePassThrough::default(), //HALT
+	0x10 => stop(cpu),
         n @ (0x70..=0x75 | 0x77) => ld_hl_r(cpu, passed, n),
         
         n @ (0x09 | 0x19 | 0x29 | 0x39) => add_hl_nn(cpu, passed, n),
@@ -142,6 +167,14 @@ pub fn instruction_decode(opcode: u8, cpu: &mut CPU, passed: StagePassThrough) -
     }
 }
 
+// STOP and its padding byte. With KEY1 armed on cgb it switches cpu speed, the low
+// power mode that waits for a button press is not emulated
+fn stop(cpu: &mut CPU) -> StagePassThrough {
+    cpu.ppu.io_registers.switch_speed();
+    cpu.pc += 2;
+    StagePassThrough::default()
+}
+
 //Adapted from Gekkio's mooneye emulator
 fn daa(cpu: &mut CPU, passed: StagePassThrough) -> StagePassThrough {
     let pass_to_next_stage = StagePassThrough::default();
@@ -0,0 +1,521 @@
+#![feature(derive_default_enum)]
+#![allow(dead_code)]
+#![allow(non_snake_case)]
+#![feature(wrapping_int_impl)]
+pub mod apu;
+pub mod cartridge;
+pub mod cpu;
+pub mod gameboy;
+pub mod instructions;
+pub mod mbc;
+pub mod ppu;
+pub mod register_maps;
+pub mod rewind;
+pub mod savestate;
+pub mod screenshot;
+pub mod sgb;
+pub mod wav;
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::*;
+    
+    #[test]
+    fn test_timer() {
+        let mut gameboy = gameboy::Gameboy::new();
+	let bin = &fs::read("./baz.gb").expect("Must provide binary!");
+	let mut i = 0;
+	gameboy.cpu.reg_file.SP = 0x8000;
+	for el in bin {
+	    gameboy.cpu.memory.boot_rom[i] = el.clone();
+	    i = i + 1;
+	}
+	let mut timer = &mut gameboy.cpu.ppu.io_registers.timer;
+	timer.tma = 0.into();
+	timer.tac = 5.into();
+
+	assert!(timer.tac.timer_enable);
+	assert_eq!(timer.tac.input_clock_select, register_maps::InputClockSelect::Mode16);
+	gameboy.run_without_graphics();
+
+	
+    }
+
+    #[test]
+    fn test_save_state_round_trip() {
+	let mut gameboy = gameboy::Gameboy::new();
+	for _ in 0..100 {
+	    gameboy.step_gameboy();
+	}
+	gameboy.cpu.reg_file.A = 0x42;
+	gameboy.cpu.addr_bus = 0xC123;
+	gameboy.cpu.write_data(0x99);
+	let state = gameboy.save_state();
+
+	gameboy.cpu.reg_file.A = 0;
+	gameboy.cpu.write_data(0);
+	for _ in 0..100 {
+	    gameboy.step_gameboy();
+	}
+	gameboy.load_state(&state).unwrap();
+	assert_eq!(gameboy.cpu.reg_file.A, 0x42);
+	assert_eq!(gameboy.cpu.pc, 100);
+	gameboy.cpu.addr_bus = 0xC123;
+	assert_eq!(gameboy.cpu.read(), 0x99);
+	assert_eq!(gameboy.save_state(), state);
+
+	assert!(gameboy.load_state(&state[..state.len() - 1]).is_err());
+	assert_eq!(gameboy.save_state(), state);
+    }
+
+    #[test]
+    fn test_headless_frame() {
+	let mut gameboy = gameboy::Gameboy::new();
+	gameboy.run_frame();
+	assert_eq!(gameboy.frame_buffer().len(), gameboy::SCREEN_WIDTH * gameboy::SCREEN_HEIGHT);
+
+	gameboy.cpu.ppu.io_registers.set(0xFF40, 0x91);
+	gameboy.cpu.ppu.io_registers.set(0xFF47, 0xFF);
+	// a frame is 17556 machine cycles of nops, kept out of vram which reads 0xFF in mode 3
+	gameboy.cpu.pc = 0;
+	gameboy.run_frame();
+	assert_eq!(gameboy.cpu.pc, 17556);
+	assert_eq!(gameboy.cpu.ppu.io_registers.ly, 0);
+	assert_eq!(gameboy.cpu.ppu.io_registers.interrupt_flag.vblank, 1);
+	let black = u32::from(&ppu::GameboyColor::Black);
+	assert!(gameboy.frame_buffer().iter().all(|&pixel| pixel == black));
+    }
+
+    #[test]
+    fn test_skip_boot() {
+	let mut gameboy = gameboy::Gameboy::new();
+	gameboy.cpu.memory.cartridge.header.header_checksum = 0x12;
+	gameboy.skip_boot();
+	let reg_file = &gameboy.cpu.reg_file;
+	assert_eq!(reg_file.A, 0x01);
+	assert_eq!(u8::from(reg_file.flags), 0xB0);
+	assert_eq!((reg_file.B, reg_file.C, reg_file.D, reg_file.E), (0x00, 0x13, 0x00, 0xD8));
+	assert_eq!((reg_file.H, reg_file.L, reg_file.SP), (0x01, 0x4D, 0xFFFE));
+	assert_eq!(gameboy.cpu.pc, 0x100);
+	assert!(!gameboy.cpu.memory.use_boot);
+	let io_registers = &gameboy.cpu.ppu.io_registers;
+	assert_eq!(io_registers.get(0xFF40), 0x91);
+	assert_eq!(io_registers.get(0xFF47), 0xFC);
+	assert_eq!(io_registers.get(0xFF04), 0xAB);
+	assert_eq!(io_registers.get(0xFF26), 0xF1);
+
+	let mut gameboy = gameboy::Gameboy::new();
+	gameboy.set_model(gameboy::Model::Cgb);
+	gameboy.skip_boot();
+	assert_eq!(gameboy.cpu.reg_file.A, 0x11);
+	assert_eq!((gameboy.cpu.reg_file.D, gameboy.cpu.reg_file.L), (0x00, 0x7C));
+
+	// only the super game boy answers packets, and only for cartridges asking for it
+	let mut gameboy = gameboy::Gameboy::new();
+	let header = &mut gameboy.cpu.memory.cartridge.header;
+	header.sgb_flag = true;
+	header.old_licensee_code = 0x33;
+	gameboy.set_model(gameboy::Model::Dmg);
+	assert!(!gameboy.cpu.ppu.io_registers.sgb.enabled);
+	gameboy.set_model(gameboy::Model::Sgb);
+	assert!(gameboy.cpu.ppu.io_registers.sgb.enabled);
+	gameboy.skip_boot();
+	assert_eq!((gameboy.cpu.reg_file.C, gameboy.cpu.reg_file.H, gameboy.cpu.reg_file.L), (0x14, 0xC0, 0x60));
+	// plain joypad polls after boot are not read as a packet
+	let palettes = gameboy.cpu.ppu.io_registers.sgb.palettes;
+	for _ in 0..200 {
+	    gameboy.cpu.ppu.io_registers.set(0xFF00, 0x20);
+	    gameboy.cpu.ppu.io_registers.set(0xFF00, 0x30);
+	}
+	assert_eq!(gameboy.cpu.ppu.io_registers.sgb.palettes, palettes);
+    }
+
+    #[test]
+    fn test_cgb_banks_and_palettes() {
+	let mut gameboy = gameboy::Gameboy::new();
+	let cpu = &mut gameboy.cpu;
+	let write = |cpu: &mut cpu::CPU, addr: u16, data: u8| {
+	    cpu.addr_bus = addr;
+	    cpu.write_data(data);
+	};
+	// dmg mode ignores the bank registers
+	write(cpu, 0xFF4F, 0x01);
+	cpu.addr_bus = 0xFF4F;
+	assert_eq!(cpu.read(), 0xFF);
+
+	cpu.ppu.io_registers.cgb_mode = true;
+	write(cpu, 0x8000, 0x11);
+	write(cpu, 0xFF4F, 0x01);
+	write(cpu, 0x8000, 0x22);
+	assert_eq!((cpu.ppu.vram[0x0000], cpu.ppu.vram[0x2000]), (0x11, 0x22));
+	cpu.addr_bus = 0xFF4F;
+	assert_eq!(cpu.read(), 0xFF);
+
+	write(cpu, 0xFF70, 0x00);
+	write(cpu, 0xD000, 0x33);
+	write(cpu, 0xFF70, 0x07);
+	write(cpu, 0xD000, 0x44);
+	write(cpu, 0xFF70, 0x01);
+	cpu.addr_bus = 0xD000;
+	assert_eq!(cpu.read(), 0x33);
+	write(cpu, 0xFF70, 0x07);
+	cpu.addr_bus = 0xD000;
+	assert_eq!(cpu.read(), 0x44);
+
+	write(cpu, 0xFF68, 0x80 | 0x3E);
+	write(cpu, 0xFF69, 0x1F);
+	write(cpu, 0xFF69, 0x7C);
+	write(cpu, 0xFF69, 0x55);
+	assert_eq!(cpu.ppu.io_registers.bg_palettes.color(7, 3), 0x7C1F);
+	assert_eq!(cpu.ppu.io_registers.bg_palettes.data[0], 0x55);
+	cpu.addr_bus = 0xFF68;
+	assert_eq!(cpu.read(), 0xC1);
+    }
+
+    #[test]
+    fn test_cgb_rendering() {
+	let mut ppu = ppu::PPU::new(register_maps::IORegisters::default());
+	ppu.io_registers.cgb_mode = true;
+	ppu.io_registers.lcdc = 0x91.into();
+	// tile 1 in bank 1, top row is color 1 in the left half and color 2 in the right half
+	ppu.vram[0x2010] = 0xF0;
+	ppu.vram[0x2011] = 0x0F;
+	// first map entry uses it with palette 2 and x flip
+	ppu.vram[0x1800] = 0x01;
+	ppu.vram[0x3800] = 0x08 | 0x20 | 0x02;
+	let palettes = &mut ppu.io_registers.bg_palettes;
+	palettes.data[2 * 8 + 2..2 * 8 + 4].copy_from_slice(&0x001Fu16.to_le_bytes());
+	palettes.data[2 * 8 + 4..2 * 8 + 6].copy_from_slice(&0x7C00u16.to_le_bytes());
+
+	render_line(&mut ppu);
+	let line = &ppu.fifo.line;
+	assert_eq!(line.len(), 160);
+	assert_eq!(line[0], ppu::Pixel::Color(0x7C00));
+	assert_eq!(line[7], ppu::Pixel::Color(0x001F));
+	assert_eq!(ppu::rgb555_to_argb(0x7C00), 0xFF0000FF);
+	assert_eq!(ppu::rgb555_to_argb(0x7FFF), 0xFFFFFFFF);
+    }
+
+    // Runs mode 3 of the current line, returns its length in dots
+    fn render_line(ppu: &mut ppu::PPU) -> u16 {
+	let sprites = ppu.object_search();
+	ppu.start_line(sprites);
+	while !ppu.step_dot() {}
+	ppu.fifo.dots
+    }
+
+    #[test]
+    fn test_pixel_fifo() {
+	use ppu::{GameboyColor, Pixel};
+	let mut ppu = ppu::PPU::new(register_maps::IORegisters::default());
+	ppu.io_registers.lcdc = 0x93.into();
+	ppu.io_registers.bgp = 0xE4;
+	ppu.io_registers.obp0 = 0xE4;
+	assert_eq!(render_line(&mut ppu), 172);
+	ppu.io_registers.scx = 3;
+	assert_eq!(render_line(&mut ppu), 175);
+	ppu.io_registers.scx = 0;
+
+	// an object on the line costs a fetch
+	ppu.oam.set(0xFE00, 16);
+	ppu.oam.set(0xFE01, 20);
+	ppu.oam.set(0xFE02, 1);
+	ppu.vram[0x10] = 0xFF;
+	assert_eq!(render_line(&mut ppu), 178);
+	assert_eq!(ppu.fifo.line[11], Pixel::Shade(GameboyColor::White));
+	assert_eq!(ppu.fifo.line[12], Pixel::Shade(GameboyColor::LightGray));
+	ppu.oam.set(0xFE00, 0);
+
+	// a palette change in the middle of the line applies from the next pixel
+	ppu.vram[0] = 0xFF;
+	ppu.vram[1] = 0xFF;
+	ppu.start_line(Vec::new());
+	for _ in 0..92 {
+	    ppu.step_dot();
+	}
+	ppu.io_registers.bgp = 0x00;
+	while !ppu.step_dot() {}
+	assert_eq!(ppu.fifo.line[79], Pixel::Shade(GameboyColor::Black));
+	assert_eq!(ppu.fifo.line[80], Pixel::Shade(GameboyColor::White));
+    }
+
+    #[test]
+    fn test_sprites() {
+	use ppu::{GameboyColor, Pixel};
+	let mut ppu = ppu::PPU::new(register_maps::IORegisters::default());
+	let set_sprite = |ppu: &mut ppu::PPU, index: usize, attributes: [u8; 4]| {
+	    for (i, value) in attributes.iter().enumerate() {
+		ppu.oam.set(0xFE00 + index * 4 + i, *value);
+	    }
+	};
+	ppu.io_registers.obp0 = 0xE4;
+	// tall objects ignore bit 0 of the tile, the flipped top row is the last of tile 3
+	ppu.io_registers.lcdc = 0x97.into();
+	ppu.vram[0x20] = 0xFF;
+	ppu.vram[0x3E] = 0xFF;
+	ppu.vram[0x3F] = 0xFF;
+	set_sprite(&mut ppu, 0, [16, 8, 3, 0x00]);
+	// parked below the screen, stays there for the rest of the test
+	set_sprite(&mut ppu, 39, [0xFF, 8, 3, 0x00]);
+	render_line(&mut ppu);
+	assert_eq!(ppu.fifo.line[0], Pixel::Shade(GameboyColor::LightGray));
+	set_sprite(&mut ppu, 0, [16, 8, 3, 0x40]);
+	render_line(&mut ppu);
+	assert_eq!(ppu.fifo.line[0], Pixel::Shade(GameboyColor::Black));
+
+	// behind the background only where its color number is not 0, whatever the shade
+	ppu.io_registers.lcdc = 0x93.into();
+	ppu.io_registers.bgp = 0xE3;
+	ppu.io_registers.obp0 = 0x54;
+	ppu.vram[0] = 0xF0;
+	ppu.vram[0x10] = 0xFF;
+	ppu.vram[0x11] = 0xFF;
+	set_sprite(&mut ppu, 0, [16, 8, 1, 0x80]);
+	render_line(&mut ppu);
+	assert_eq!(ppu.fifo.line[3], Pixel::Shade(GameboyColor::White));
+	assert_eq!(ppu.fifo.line[4], Pixel::Shade(GameboyColor::LightGray));
+	assert_eq!(ppu.fifo.line[12], Pixel::Shade(GameboyColor::Black));
+
+	// lcdc bit 1 hides objects and skips their fetch
+	ppu.io_registers.lcdc = 0x91.into();
+	assert_eq!(render_line(&mut ppu), 172);
+	assert_eq!(ppu.fifo.line[4], Pixel::Shade(GameboyColor::Black));
+	// objects passed while it was clear stay dropped when it is set in the line
+	set_sprite(&mut ppu, 1, [16, 20, 1, 0x00]);
+	set_sprite(&mut ppu, 2, [16, 120, 1, 0x00]);
+	let sprites = ppu.object_search();
+	ppu.start_line(sprites);
+	for _ in 0..100 {
+	    ppu.step_dot();
+	}
+	ppu.io_registers.lcdc = 0x93.into();
+	while !ppu.step_dot() {}
+	assert_eq!(ppu.fifo.dots, 178);
+	assert_eq!(ppu.fifo.line[12], Pixel::Shade(GameboyColor::Black));
+	assert_eq!(ppu.fifo.line[112], Pixel::Shade(GameboyColor::LightGray));
+
+	// the lower x wins, then the lower oam index
+	ppu.io_registers.lcdc = 0x93.into();
+	ppu.io_registers.obp1 = 0xA8;
+	ppu.vram[0] = 0x00;
+	set_sprite(&mut ppu, 0, [16, 5, 1, 0x10]);
+	set_sprite(&mut ppu, 1, [16, 2, 1, 0x00]);
+	set_sprite(&mut ppu, 2, [16, 100, 1, 0x10]);
+	set_sprite(&mut ppu, 3, [16, 100, 1, 0x00]);
+	render_line(&mut ppu);
+	assert_eq!(ppu.fifo.line[1], Pixel::Shade(GameboyColor::LightGray));
+	assert_eq!(ppu.fifo.line[2], Pixel::Shade(GameboyColor::DarkGray));
+	assert_eq!(ppu.fifo.line[92], Pixel::Shade(GameboyColor::DarkGray));
+    }
+
+    #[test]
+    fn test_window() {
+	use ppu::{GameboyColor, Pixel};
+	let mut ppu = ppu::PPU::new(register_maps::IORegisters::default());
+	let tick_until = |ppu: &mut ppu::PPU, done: &dyn Fn(&ppu::PPU) -> bool| {
+	    while !done(ppu) {
+		ppu.tick(1);
+	    }
+	};
+	let shade = |ppu: &ppu::PPU, y: usize, x: usize| ppu.screen[y * 160 + x];
+	ppu.io_registers.bgp = 0xE4;
+	// tile 1 is white on its left half, 2 is light gray and 3 dark gray
+	for row in 0..8 {
+	    ppu.vram[0x10 + row * 2] = 0x0F;
+	    ppu.vram[0x11 + row * 2] = 0x0F;
+	    ppu.vram[0x20 + row * 2] = 0xFF;
+	    ppu.vram[0x31 + row * 2] = 0xFF;
+	}
+	// window rows 0 - 7 start with tile 1 then light gray, rows 8 - 15 start with tile 1
+	// then dark gray and 16 - 23 are light gray
+	for column in 0..32 {
+	    ppu.vram[0x1C00 + column] = if column == 0 { 1 } else { 2 };
+	    ppu.vram[0x1C20 + column] = if column == 0 { 1 } else { 3 };
+	    ppu.vram[0x1C40 + column] = 2;
+	}
+	ppu.io_registers.wy = 2;
+	ppu.io_registers.wx = 27;
+	ppu.io_registers.lcdc = 0xF1.into();
+
+	// the window line only counts lines the window was drawn on
+	tick_until(&mut ppu, &|ppu| ppu.io_registers.ly == 6);
+	ppu.io_registers.wx = 255;
+	tick_until(&mut ppu, &|ppu| ppu.io_registers.ly == 10);
+	ppu.io_registers.wx = 27;
+	// moving wx ahead of the pixels in the middle of a line starts the window over
+	tick_until(&mut ppu, &|ppu| ppu.io_registers.ly == 20 && ppu.fifo.line.len() == 40);
+	ppu.io_registers.wx = 107;
+	tick_until(&mut ppu, &|ppu| ppu.io_registers.ly == 21);
+	ppu.io_registers.wx = 27;
+	tick_until(&mut ppu, &|ppu| ppu.frame_ready);
+	assert_eq!(shade(&ppu, 1, 28), Pixel::Shade(GameboyColor::White));
+	assert_eq!(shade(&ppu, 2, 20), Pixel::Shade(GameboyColor::White));
+	assert_eq!(shade(&ppu, 2, 24), Pixel::Shade(GameboyColor::Black));
+	assert_eq!(shade(&ppu, 2, 28), Pixel::Shade(GameboyColor::LightGray));
+	assert_eq!(shade(&ppu, 6, 28), Pixel::Shade(GameboyColor::White));
+	assert_eq!(shade(&ppu, 13, 28), Pixel::Shade(GameboyColor::LightGray));
+	assert_eq!(shade(&ppu, 14, 28), Pixel::Shade(GameboyColor::DarkGray));
+	assert_eq!(shade(&ppu, 20, 99), Pixel::Shade(GameboyColor::DarkGray));
+	assert_eq!(shade(&ppu, 20, 100), Pixel::Shade(GameboyColor::White));
+	assert_eq!(shade(&ppu, 20, 104), Pixel::Shade(GameboyColor::Black));
+	assert_eq!(shade(&ppu, 20, 108), Pixel::Shade(GameboyColor::DarkGray));
+
+	// below 7 the window starts off the left edge, at 166 only its first pixel shows
+	ppu.frame_ready = false;
+	ppu.io_registers.wy = 0;
+	ppu.io_registers.wx = 3;
+	tick_until(&mut ppu, &|ppu| ppu.io_registers.ly == 16);
+	ppu.io_registers.wx = 166;
+	tick_until(&mut ppu, &|ppu| ppu.frame_ready);
+	assert_eq!(shade(&ppu, 0, 0), Pixel::Shade(GameboyColor::Black));
+	assert_eq!(shade(&ppu, 0, 4), Pixel::Shade(GameboyColor::LightGray));
+	assert_eq!(shade(&ppu, 16, 158), Pixel::Shade(GameboyColor::White));
+	assert_eq!(shade(&ppu, 16, 159), Pixel::Shade(GameboyColor::LightGray));
+    }
+
+    #[test]
+    fn test_ppu_timing() {
+	let mut gameboy = gameboy::Gameboy::new();
+	let cpu = &mut gameboy.cpu;
+	cpu.ppu.io_registers.set(0xFF45, 2);
+	cpu.ppu.io_registers.set(0xFF41, 0x40);
+	cpu.ppu.io_registers.set(0xFF40, 0x91);
+	cpu.step(false);
+	assert_eq!(cpu.ppu.io_registers.get(0xFF41) & 0x03, 2);
+	for _ in 1..20 {
+	    cpu.step(false);
+	}
+	// mode 3 locks vram for the cpu
+	assert_eq!(cpu.mode, ppu::Mode::Mode3);
+	cpu.ppu.vram[0] = 0x12;
+	cpu.addr_bus = 0x8000;
+	assert_eq!(cpu.read(), 0xFF);
+	for _ in 20..114 {
+	    cpu.step(false);
+	}
+	assert_eq!((cpu.ppu.io_registers.ly, cpu.mode), (1, ppu::Mode::Mode2));
+	assert_eq!(cpu.ppu.io_registers.interrupt_flag.lcd_stat, 0);
+	for _ in 0..114 {
+	    cpu.step(false);
+	}
+	assert_eq!(cpu.ppu.io_registers.interrupt_flag.lcd_stat, 1);
+	assert_eq!(cpu.ppu.io_registers.get(0xFF41) & 0x04, 0x04);
+	for _ in 0..142 * 114 {
+	    cpu.step(false);
+	}
+	assert_eq!((cpu.ppu.io_registers.ly, cpu.mode), (144, ppu::Mode::Mode1));
+	assert_eq!(cpu.ppu.io_registers.interrupt_flag.vblank, 1);
+	assert!(!cpu.ppu.frame_ready);
+	for _ in 0..10 * 114 {
+	    cpu.step(false);
+	}
+	assert!(cpu.ppu.frame_ready);
+	assert_eq!(cpu.ppu.io_registers.ly, 0);
+    }
+
+    #[test]
+    fn test_stat_line() {
+	let mut gameboy = gameboy::Gameboy::new();
+	let cpu = &mut gameboy.cpu;
+	let step_until = |cpu: &mut cpu::CPU, done: &dyn Fn(&cpu::CPU) -> bool| {
+	    while !done(cpu) {
+		cpu.step(false);
+	    }
+	};
+	cpu.ppu.io_registers.set(0xFF45, 1);
+	cpu.ppu.io_registers.set(0xFF41, 0x48);
+	cpu.ppu.io_registers.set(0xFF40, 0x91);
+	step_until(cpu, &|cpu| cpu.mode == ppu::Mode::Mode0);
+	assert_eq!(cpu.ppu.io_registers.interrupt_flag.lcd_stat, 1);
+	cpu.ppu.io_registers.interrupt_flag.lcd_stat = 0;
+	// h-blank hands the line straight to LY=LYC on line 1, which then holds it up
+	// through the next h-blank
+	step_until(cpu, &|cpu| cpu.ppu.io_registers.ly == 1);
+	step_until(cpu, &|cpu| cpu.mode == ppu::Mode::Mode0);
+	assert_eq!(cpu.ppu.io_registers.interrupt_flag.lcd_stat, 0);
+	step_until(cpu, &|cpu| cpu.ppu.io_registers.ly == 2);
+	step_until(cpu, &|cpu| cpu.mode == ppu::Mode::Mode0);
+	assert_eq!(cpu.ppu.io_registers.interrupt_flag.lcd_stat, 1);
+
+	// LY=LYC for line 0 already matches on line 153
+	cpu.ppu.io_registers.set(0xFF41, 0x40);
+	cpu.ppu.io_registers.set(0xFF45, 0);
+	step_until(cpu, &|cpu| cpu.ppu.io_registers.ly == 152);
+	cpu.ppu.io_registers.interrupt_flag.lcd_stat = 0;
+	step_until(cpu, &|cpu| cpu.ppu.io_registers.ly != 152);
+	assert_eq!(cpu.ppu.io_registers.ly, 153);
+	cpu.step(false);
+	cpu.step(false);
+	assert_eq!(cpu.ppu.io_registers.ly, 0);
+	assert_eq!(cpu.mode, ppu::Mode::Mode1);
+	assert_eq!(cpu.ppu.io_registers.interrupt_flag.lcd_stat, 1);
+	cpu.ppu.io_registers.interrupt_flag.lcd_stat = 0;
+	step_until(cpu, &|cpu| cpu.mode == ppu::Mode::Mode2);
+	assert_eq!(cpu.ppu.io_registers.interrupt_flag.lcd_stat, 0);
+
+	// writing stat on the dmg outside of mode 3 fires once while the line is low
+	step_until(cpu, &|cpu| cpu.ppu.io_registers.ly == 1);
+	step_until(cpu, &|cpu| cpu.mode == ppu::Mode::Mode0);
+	cpu.ppu.io_registers.set(0xFF41, 0x00);
+	assert_eq!(cpu.ppu.io_registers.interrupt_flag.lcd_stat, 1);
+	assert_eq!(cpu.ppu.io_registers.get(0xFF41) & 0x03, 0);
+	cpu.ppu.io_registers.interrupt_flag.lcd_stat = 0;
+	cpu.ppu.io_registers.cgb_mode = true;
+	cpu.step(false);
+	cpu.ppu.io_registers.set(0xFF41, 0x00);
+	assert_eq!(cpu.ppu.io_registers.interrupt_flag.lcd_stat, 0);
+    }
+
+    #[test]
+    fn test_vram_dma() {
+	let mut gameboy = gameboy::Gameboy::new();
+	let cpu = &mut gameboy.cpu;
+	cpu.ppu.io_registers.cgb_mode = true;
+	let write = |cpu: &mut cpu::CPU, addr: u16, data: u8| {
+	    cpu.addr_bus = addr;
+	    cpu.write_data(data);
+	};
+	for i in 0..0x40 {
+	    write(cpu, 0xC000 + i, i as u8);
+	}
+	write(cpu, 0xFF51, 0xC0);
+	write(cpu, 0xFF52, 0x00);
+	write(cpu, 0xFF53, 0x01);
+	write(cpu, 0xFF54, 0x00);
+	// general purpose, two blocks
+	write(cpu, 0xFF55, 0x01);
+	assert_eq!(cpu.ppu.vram[0x100..0x120], (0..0x20).collect::<Vec<u8>>()[..]);
+	assert_eq!(cpu.ppu.vram[0x120], 0);
+	assert_eq!(cpu.ppu.io_registers.hdma.stall_cycles, 16);
+	cpu.addr_bus = 0xFF55;
+	assert_eq!(cpu.read(), 0xFF);
+
+	// h-blank, three blocks, cancelled after the first
+	cpu.ppu.io_registers.lcdc.lcd_ppu_enable = 1;
+	write(cpu, 0xFF55, 0x82);
+	assert_eq!(cpu.ppu.vram[0x120], 0);
+	cpu.hblank_dma();
+	assert_eq!(cpu.ppu.vram[0x120..0x130], (0x20..0x30).collect::<Vec<u8>>()[..]);
+	cpu.addr_bus = 0xFF55;
+	assert_eq!(cpu.read(), 0x01);
+	write(cpu, 0xFF55, 0x00);
+	cpu.hblank_dma();
+	assert_eq!(cpu.ppu.vram[0x130], 0);
+	cpu.addr_bus = 0xFF55;
+	assert_eq!(cpu.read(), 0x81);
+    }
+
+    #[test]
+    fn test_speed_switch() {
+	let mut gameboy = gameboy::Gameboy::new();
+	gameboy.cpu.ppu.io_registers.cgb_mode = true;
+	gameboy.cpu.ppu.io_registers.set(0xFF4D, 0x01);
+	gameboy.cpu.addr_bus = 0xC000;
+	gameboy.cpu.write_data(0x10);
+	gameboy.cpu.pc = 0xC000;
+	gameboy.step_gameboy();
+	assert_eq!(gameboy.cpu.ppu.io_registers.get(0xFF4D), 0xFE);
+	assert_eq!(gameboy.cpu.pc, 0xC002);
+    }
+}
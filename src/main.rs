@@ -1,29 +1,98 @@
-#![feature(derive_default_enum)]
-#![allow(dead_code)]
-#![allow(non_snake_case)]
-#![feature(wrapping_int_impl)]
-mod cpu;
-mod gameboy;
-mod instructions;
-mod ppu;
-mod register_maps;
+mod audio;
+mod cli;
+mod frontend;
+use cli::{CliError, Options, DEFAULT_BOOT_ROM, USAGE};
+use frontend::Frontend;
+use rustboy::gameboy::{Gameboy, Model};
 use sdl2::{
     render::{Canvas, TextureCreator},
     video::{Window, WindowContext},
 };
+use std::error::Error;
+use std::path::PathBuf;
+use std::process;
 
 const X_DIM: u32 = 160;
 const Y_DIM: u32 = 144;
-const SCALE: u32 = 8;
 
 fn main() {
-    let sdl_context = sdl2::init().unwrap();
-    let video_subsystem = sdl_context.video().unwrap();
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(CliError::Help) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(CliError::Usage(message)) => {
+            eprintln!("rustboy: {}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(err) = run(options) {
+        eprintln!("rustboy: {}", err);
+        process::exit(1);
+    }
+}
+
+fn run(options: Options) -> Result<(), Box<dyn Error>> {
+    let mut gameboy = Gameboy::new();
+    gameboy.palette = options.palette;
+    gameboy.load_rom(&options.rom)?;
+
+    // Without an explicit boot rom the bundled one is used when it is around
+    let default_boot_rom = PathBuf::from(DEFAULT_BOOT_ROM);
+    let boot_rom = match &options.boot_rom {
+        Some(path) => Some(path.clone()),
+        None if !options.skip_boot && default_boot_rom.exists() => Some(default_boot_rom),
+        None => None,
+    };
+    // The bundled boot rom is a dmg one, skipping it follows the cartridge instead
+    let model = match (options.model, &boot_rom) {
+        (Some(model), _) => model,
+        (None, Some(_)) => Model::Dmg,
+        (None, None) => Model::for_cartridge(&gameboy.cpu.memory.cartridge.header),
+    };
+    gameboy.set_model(model);
+    match boot_rom {
+        Some(path) => gameboy.load_boot_rom(&path)?,
+        None => gameboy.skip_boot(),
+    }
+    if let Some(path) = &options.record_audio {
+        gameboy.start_recording(path.clone());
+    }
+
+    // Options::parse makes sure headless runs have a frame limit
+    let mut gameboy = match options.frame_limit {
+        Some(frames) if options.headless => run_headless(gameboy, frames),
+        _ => run_window(gameboy, &options)?,
+    };
+
+    gameboy.shutdown();
+    if let Some(path) = &options.screenshot {
+        gameboy
+            .save_screenshot(path)
+            .map_err(|err| format!("could not write {}: {}", path.display(), err))?;
+    }
+    Ok(())
+}
+
+// Unpaced and without sdl, for scripted runs
+fn run_headless(mut gameboy: Gameboy, frames: u32) -> Gameboy {
+    for _ in 0..frames {
+        gameboy.run_frame();
+        let samples = gameboy.cpu.ppu.io_registers.apu.take_samples();
+        gameboy.write_recordings(&samples);
+    }
+    gameboy
+}
+
+fn run_window(gameboy: Gameboy, options: &Options) -> Result<Gameboy, Box<dyn Error>> {
+    let scale = options.scale;
+    let sdl_context = sdl2::init()?;
+    let video_subsystem = sdl_context.video()?;
     let window = video_subsystem
-        .window("Gameboy Window", X_DIM * SCALE, Y_DIM * SCALE)
+        .window("Gameboy Window", X_DIM * scale, Y_DIM * scale)
         .opengl()
-        .build()
-        .unwrap();
+        .build()?;
 
     /*
     let debug_window = video_subsystem
@@ -33,8 +102,8 @@ fn main() {
         .unwrap();
      */
     
-    let mut canvas: Canvas<Window> = window.into_canvas().present_vsync().build().unwrap();
-    canvas.set_scale(SCALE as f32, SCALE as f32).unwrap();
+    let mut canvas: Canvas<Window> = window.into_canvas().present_vsync().build()?;
+    canvas.set_scale(scale as f32, scale as f32)?;
 
     //let mut debug_canvas = debug_window.into_canvas().present_vsync().build().unwrap();
 //    debug_canvas.set_scale(SCALE as f32, SCALE as f32).unwrap();
@@ -42,34 +111,9 @@ fn main() {
     let texture_creator: TextureCreator<WindowContext> = canvas.texture_creator();
 //    let debug_texture_creator = debug_canvas.texture_creator();
     
-    let mut gameboy = gameboy::Gameboy::new();
-    gameboy.init_gameboy();
-    gameboy.run_emulator(&sdl_context, &texture_creator, &mut canvas);
-}
-
-#[cfg(test)]
-mod tests {
-    use std::fs;
-    use super::*;
-    
-    #[test]
-    fn test_timer() {
-        let mut gameboy = gameboy::Gameboy::new();
-	let bin = &fs::read("./baz.gb").expect("Must provide binary!");
-	let mut i = 0;
-	gameboy.cpu.reg_file.SP = 0x8000;
-	for el in bin {
-	    gameboy.cpu.memory.boot_rom[i] = el.clone();
-	    i = i + 1;
-	}
-	let mut timer = &mut gameboy.cpu.ppu.io_registers.timer;
-	timer.tma = 0.into();
-	timer.tac = 5.into();
-
-	assert!(timer.tac.timer_enable);
-	assert_eq!(timer.tac.input_clock_select, register_maps::InputClockSelect::Mode16);
-	gameboy.run_without_graphics();
-
-	
-    }
+    let mut frontend = Frontend::new(gameboy);
+    frontend.mute = options.mute;
+    frontend.frame_limit = options.frame_limit;
+    frontend.run(&sdl_context, &texture_creator, &mut canvas);
+    Ok(frontend.gameboy)
 }
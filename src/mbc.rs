@@ -0,0 +1,158 @@
+mod mbc1;
+mod mbc2;
+mod mbc3;
+mod mbc5;
+mod rtc;
+
+pub use mbc1::Mbc1;
+pub use mbc2::{Mbc2, MBC2_RAM_SIZE};
+pub use mbc3::Mbc3;
+pub use mbc5::Mbc5;
+pub use rtc::{Rtc, RtcClock, SystemClock, RTC_FOOTER_SIZE};
+#[cfg(test)]
+pub use rtc::tests::TestClock;
+
+use crate::cartridge::{CartridgeError, CartridgeHeader, Mapper};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+// Bank controller state, the rom and ram themselves are owned by the Cartridge
+pub enum Mbc {
+    RomOnly,
+    Mbc1(Mbc1),
+    Mbc2(Mbc2),
+    Mbc3(Mbc3),
+    Mbc5(Mbc5),
+}
+
+impl Mbc {
+    pub fn new(header: &CartridgeHeader, rom: &[u8]) -> Result<Mbc, CartridgeError> {
+        match header.cartridge_type.mapper {
+            Mapper::None => Ok(Mbc::RomOnly),
+            Mapper::Mbc1 => Ok(Mbc::Mbc1(Mbc1::new(rom))),
+            Mapper::Mbc2 => Ok(Mbc::Mbc2(Mbc2::new())),
+            Mapper::Mbc3 => {
+                let clock: Option<Box<dyn RtcClock>> = if header.cartridge_type.timer {
+                    Some(Box::new(SystemClock))
+                } else {
+                    None
+                };
+                Ok(Mbc::Mbc3(Mbc3::new(clock)))
+            }
+            Mapper::Mbc5 => Ok(Mbc::Mbc5(Mbc5::new(header.cartridge_type.rumble))),
+            mapper => Err(CartridgeError::UnsupportedMapper(mapper)),
+        }
+    }
+
+    pub fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        match self {
+            Mbc::RomOnly => rom.get(addr as usize).copied().unwrap_or(0xFF),
+            Mbc::Mbc1(mbc) => mbc.read_rom(rom, addr),
+            Mbc::Mbc2(mbc) => mbc.read_rom(rom, addr),
+            Mbc::Mbc3(mbc) => mbc.read_rom(rom, addr),
+            Mbc::Mbc5(mbc) => mbc.read_rom(rom, addr),
+        }
+    }
+
+    pub fn write_rom(&mut self, addr: u16, data: u8) {
+        match self {
+            Mbc::RomOnly => (),
+            Mbc::Mbc1(mbc) => mbc.write_rom(addr, data),
+            Mbc::Mbc2(mbc) => mbc.write_rom(addr, data),
+            Mbc::Mbc3(mbc) => mbc.write_rom(addr, data),
+            Mbc::Mbc5(mbc) => mbc.write_rom(addr, data),
+        }
+    }
+
+    pub fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        match self {
+            Mbc::RomOnly => ram.get(addr as usize - 0xA000).copied().unwrap_or(0xFF),
+            Mbc::Mbc1(mbc) => mbc.read_ram(ram, addr),
+            Mbc::Mbc2(mbc) => mbc.read_ram(ram, addr),
+            Mbc::Mbc3(mbc) => mbc.read_ram(ram, addr),
+            Mbc::Mbc5(mbc) => mbc.read_ram(ram, addr),
+        }
+    }
+
+    pub fn write_ram(&mut self, ram: &mut [u8], addr: u16, data: u8) {
+        match self {
+            Mbc::RomOnly => {
+                if let Some(byte) = ram.get_mut(addr as usize - 0xA000) {
+                    *byte = data;
+                }
+            }
+            Mbc::Mbc1(mbc) => mbc.write_ram(ram, addr, data),
+            Mbc::Mbc2(mbc) => mbc.write_ram(ram, addr, data),
+            Mbc::Mbc3(mbc) => mbc.write_ram(ram, addr, data),
+            Mbc::Mbc5(mbc) => mbc.write_ram(ram, addr, data),
+        }
+    }
+
+    pub fn rumble(&self) -> bool {
+        match self {
+            Mbc::Mbc5(mbc) => mbc.rumble,
+            _ => false,
+        }
+    }
+
+    pub fn rtc(&self) -> Option<&Rtc> {
+        match self {
+            Mbc::Mbc3(mbc) => mbc.rtc.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        match self {
+            Mbc::Mbc3(mbc) => mbc.rtc.as_mut(),
+            _ => None,
+        }
+    }
+}
+
+impl SaveState for Mbc {
+    fn save_state(&self, writer: &mut StateWriter) {
+        match self {
+            Mbc::RomOnly => writer.u8(0),
+            Mbc::Mbc1(mbc) => {
+                writer.u8(1);
+                mbc.save_state(writer);
+            }
+            Mbc::Mbc2(mbc) => {
+                writer.u8(2);
+                mbc.save_state(writer);
+            }
+            Mbc::Mbc3(mbc) => {
+                writer.u8(3);
+                mbc.save_state(writer);
+            }
+            Mbc::Mbc5(mbc) => {
+                writer.u8(5);
+                mbc.save_state(writer);
+            }
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        match (reader.u8()?, self) {
+            (0, Mbc::RomOnly) => Ok(()),
+            (1, Mbc::Mbc1(mbc)) => mbc.load_state(reader),
+            (2, Mbc::Mbc2(mbc)) => mbc.load_state(reader),
+            (3, Mbc::Mbc3(mbc)) => mbc.load_state(reader),
+            (5, Mbc::Mbc5(mbc)) => mbc.load_state(reader),
+            _ => Err(StateError::RomMismatch),
+        }
+    }
+}
+
+// Maps a bank number onto the rom, wrapping like the unconnected upper address lines do
+pub fn rom_offset(rom: &[u8], bank: usize, addr: u16) -> usize {
+    let banks = (rom.len() / ROM_BANK_SIZE).max(1);
+    (bank % banks) * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1))
+}
+
+pub fn ram_offset(ram: &[u8], bank: usize, addr: u16) -> usize {
+    (bank * RAM_BANK_SIZE + (addr as usize & (RAM_BANK_SIZE - 1))) % ram.len()
+}
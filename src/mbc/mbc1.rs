@@ -0,0 +1,209 @@
+use crate::mbc::{ram_offset, rom_offset, ROM_BANK_SIZE};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+const LOGO_START: usize = 0x0104;
+const LOGO_END: usize = 0x0134;
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Mbc1 {
+    pub ram_enable: bool,
+    pub rom_bank: u8,     // 0x2000 - 0x3FFF, 5 bits
+    pub upper_bank: u8,   // 0x4000 - 0x5FFF, 2 bits, upper rom bits or ram bank
+    pub banking_mode: u8, // 0x6000 - 0x7FFF
+    pub multicart: bool,
+}
+
+impl Mbc1 {
+    pub fn new(rom: &[u8]) -> Mbc1 {
+        Mbc1 {
+            rom_bank: 1,
+            multicart: Mbc1::is_multicart(rom),
+            ..Default::default()
+        }
+    }
+
+    // MBC1M carts wire bank bit 4 to the upper register instead of bit 5, so every
+    // game in the collection starts at a multiple of 0x10 banks with its own header
+    fn is_multicart(rom: &[u8]) -> bool {
+        if rom.len() != 64 * ROM_BANK_SIZE {
+            return false;
+        }
+        let logo = &rom[LOGO_START..LOGO_END];
+        (1..4)
+            .map(|game| game * 0x10 * ROM_BANK_SIZE)
+            .filter(|base| &rom[base + LOGO_START..base + LOGO_END] == logo)
+            .count()
+            >= 2
+    }
+
+    fn upper_shift(&self) -> u8 {
+        if self.multicart {
+            4
+        } else {
+            5
+        }
+    }
+
+    fn low_bank(&self) -> usize {
+        if self.banking_mode == 1 {
+            (self.upper_bank << self.upper_shift()) as usize
+        } else {
+            0
+        }
+    }
+
+    fn high_bank(&self) -> usize {
+        let low_bits = if self.multicart {
+            self.rom_bank & 0x0F
+        } else {
+            self.rom_bank
+        };
+        ((self.upper_bank << self.upper_shift()) | low_bits) as usize
+    }
+
+    fn ram_bank(&self) -> usize {
+        if self.banking_mode == 1 {
+            self.upper_bank as usize
+        } else {
+            0
+        }
+    }
+
+    pub fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        let bank = match addr {
+            0x0000..=0x3FFF => self.low_bank(),
+            _ => self.high_bank(),
+        };
+        rom[rom_offset(rom, bank, addr)]
+    }
+
+    pub fn write_rom(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enable = data & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                // the zero check only looks at the 5 register bits, hence the 0x20/0x40/0x60 quirk
+                self.rom_bank = data & 0x1F;
+                if self.rom_bank == 0 {
+                    self.rom_bank = 1;
+                }
+            }
+            0x4000..=0x5FFF => self.upper_bank = data & 0x03,
+            0x6000..=0x7FFF => self.banking_mode = data & 0x01,
+            _ => panic!("invalid mbc1 rom address: {:#x}", addr),
+        }
+    }
+
+    pub fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        if !self.ram_enable || ram.is_empty() {
+            return 0xFF;
+        }
+        ram[ram_offset(ram, self.ram_bank(), addr)]
+    }
+
+    pub fn write_ram(&mut self, ram: &mut [u8], addr: u16, data: u8) {
+        if !self.ram_enable || ram.is_empty() {
+            return;
+        }
+        ram[ram_offset(ram, self.ram_bank(), addr)] = data;
+    }
+}
+
+impl SaveState for Mbc1 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.ram_enable);
+        writer.u8(self.rom_bank);
+        writer.u8(self.upper_bank);
+        writer.u8(self.banking_mode);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enable = reader.bool()?;
+        self.rom_bank = reader.u8()?;
+        self.upper_bank = reader.u8()?;
+        self.banking_mode = reader.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every bank starts with its own bank number so reads show which bank is mapped
+    fn make_rom(banks: usize) -> Vec<u8> {
+        let mut rom = vec![0; banks * ROM_BANK_SIZE];
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+        }
+        rom
+    }
+
+    #[test]
+    fn test_rom_banking() {
+        let rom = make_rom(128);
+        let mut mbc = Mbc1::new(&rom);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 1);
+        mbc.write_rom(0x2000, 0x05);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 5);
+        mbc.write_rom(0x4000, 0x02);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x45);
+        // mode 0 keeps bank 0 fixed at 0x0000
+        assert_eq!(mbc.read_rom(&rom, 0x0000), 0);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_rom(&rom, 0x0000), 0x40);
+    }
+
+    #[test]
+    fn test_bank_zero_remap() {
+        let rom = make_rom(128);
+        let mut mbc = Mbc1::new(&rom);
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 1);
+        mbc.write_rom(0x4000, 0x01);
+        mbc.write_rom(0x2000, 0x20);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x21);
+    }
+
+    #[test]
+    fn test_rom_bank_wraps_to_rom_size() {
+        let rom = make_rom(4);
+        let mut mbc = Mbc1::new(&rom);
+        mbc.write_rom(0x2000, 0x06);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 2);
+    }
+
+    #[test]
+    fn test_ram_banking() {
+        let rom = make_rom(4);
+        let mut ram = vec![0; 0x8000];
+        let mut mbc = Mbc1::new(&rom);
+        mbc.write_ram(&mut ram, 0xA000, 0x12);
+        assert_eq!(mbc.read_ram(&ram, 0xA000), 0xFF);
+
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_rom(0x6000, 0x01);
+        mbc.write_rom(0x4000, 0x02);
+        mbc.write_ram(&mut ram, 0xA000, 0x34);
+        assert_eq!(ram[0x4000], 0x34);
+
+        // mode 0 always uses ram bank 0
+        mbc.write_rom(0x6000, 0x00);
+        assert_eq!(mbc.read_ram(&ram, 0xA000), 0x00);
+    }
+
+    #[test]
+    fn test_multicart() {
+        let mut rom = make_rom(64);
+        for game in 0..4 {
+            let base = game * 0x10 * ROM_BANK_SIZE;
+            rom[base + LOGO_START..base + LOGO_END].copy_from_slice(&[0xCE; 0x30]);
+        }
+        let mut mbc = Mbc1::new(&rom);
+        assert!(mbc.multicart);
+        mbc.write_rom(0x4000, 0x01);
+        mbc.write_rom(0x2000, 0x12);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x12);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_rom(&rom, 0x0000), 0x10);
+    }
+}
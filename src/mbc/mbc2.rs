@@ -0,0 +1,96 @@
+use crate::mbc::rom_offset;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+pub const MBC2_RAM_SIZE: usize = 0x200;
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Mbc2 {
+    pub ram_enable: bool,
+    pub rom_bank: u8, // 4 bits
+}
+
+impl Mbc2 {
+    pub fn new() -> Mbc2 {
+        Mbc2 {
+            ram_enable: false,
+            rom_bank: 1,
+        }
+    }
+
+    pub fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        let bank = match addr {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank as usize,
+        };
+        rom[rom_offset(rom, bank, addr)]
+    }
+
+    // Both registers live in 0x0000 - 0x3FFF, address bit 8 picks which one is written
+    pub fn write_rom(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x0000..=0x3FFF => {
+                if addr & 0x0100 == 0 {
+                    self.ram_enable = data & 0x0F == 0x0A;
+                } else {
+                    self.rom_bank = data & 0x0F;
+                    if self.rom_bank == 0 {
+                        self.rom_bank = 1;
+                    }
+                }
+            }
+            0x4000..=0x7FFF => (),
+            _ => panic!("invalid mbc2 rom address: {:#x}", addr),
+        }
+    }
+
+    // Only the low nibble is backed by ram, the upper nibble floats high.
+    // The 512 entries repeat across the whole 0xA000 - 0xBFFF window
+    pub fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        if !self.ram_enable {
+            return 0xFF;
+        }
+        0xF0 | ram[addr as usize & (MBC2_RAM_SIZE - 1)]
+    }
+
+    pub fn write_ram(&mut self, ram: &mut [u8], addr: u16, data: u8) {
+        if !self.ram_enable {
+            return;
+        }
+        ram[addr as usize & (MBC2_RAM_SIZE - 1)] = data & 0x0F;
+    }
+}
+
+impl SaveState for Mbc2 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.ram_enable);
+        writer.u8(self.rom_bank);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enable = reader.bool()?;
+        self.rom_bank = reader.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_select_and_nibble_ram() {
+        let mut ram = vec![0; MBC2_RAM_SIZE];
+        let mut mbc = Mbc2::new();
+        mbc.write_rom(0x0100, 0x0A);
+        assert!(!mbc.ram_enable);
+        assert_eq!(mbc.rom_bank, 0x0A);
+        mbc.write_rom(0x0000, 0x0A);
+        assert!(mbc.ram_enable);
+
+        mbc.write_ram(&mut ram, 0xA005, 0xAB);
+        assert_eq!(ram[0x005], 0x0B);
+        assert_eq!(mbc.read_ram(&ram, 0xA005), 0xFB);
+        assert_eq!(mbc.read_ram(&ram, 0xA205), 0xFB);
+        assert_eq!(mbc.read_ram(&ram, 0xBE05), 0xFB);
+    }
+}
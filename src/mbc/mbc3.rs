@@ -0,0 +1,162 @@
+use crate::mbc::rtc::{Rtc, RtcClock};
+use crate::mbc::{ram_offset, rom_offset};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+pub struct Mbc3 {
+    pub ram_timer_enable: bool,
+    pub rom_bank: u8,   // 0x2000 - 0x3FFF, 7 bits
+    pub ram_select: u8, // 0x4000 - 0x5FFF, 0x00 - 0x03 ram bank, 0x08 - 0x0C rtc register
+    pub latch: u8,      // 0x6000 - 0x7FFF, last value written
+    pub rtc: Option<Rtc>,
+}
+
+impl Mbc3 {
+    pub fn new(clock: Option<Box<dyn RtcClock>>) -> Mbc3 {
+        Mbc3 {
+            ram_timer_enable: false,
+            rom_bank: 1,
+            ram_select: 0,
+            latch: 0xFF,
+            rtc: clock.map(Rtc::new),
+        }
+    }
+
+    pub fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        let bank = match addr {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank as usize,
+        };
+        rom[rom_offset(rom, bank, addr)]
+    }
+
+    pub fn write_rom(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_timer_enable = data & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                self.rom_bank = data & 0x7F;
+                if self.rom_bank == 0 {
+                    self.rom_bank = 1;
+                }
+            }
+            0x4000..=0x5FFF => self.ram_select = data,
+            0x6000..=0x7FFF => {
+                if self.latch == 0x00 && data == 0x01 {
+                    if let Some(rtc) = self.rtc.as_mut() {
+                        rtc.latch();
+                    }
+                }
+                self.latch = data;
+            }
+            _ => panic!("invalid mbc3 rom address: {:#x}", addr),
+        }
+    }
+
+    pub fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        if !self.ram_timer_enable {
+            return 0xFF;
+        }
+        match self.ram_select {
+            0x00..=0x03 if !ram.is_empty() => {
+                ram[ram_offset(ram, self.ram_select as usize, addr)]
+            }
+            reg @ 0x08..=0x0C => match self.rtc.as_ref() {
+                Some(rtc) => rtc.read(reg),
+                None => 0xFF,
+            },
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_ram(&mut self, ram: &mut [u8], addr: u16, data: u8) {
+        if !self.ram_timer_enable {
+            return;
+        }
+        match self.ram_select {
+            0x00..=0x03 if !ram.is_empty() => {
+                ram[ram_offset(ram, self.ram_select as usize, addr)] = data
+            }
+            reg @ 0x08..=0x0C => {
+                if let Some(rtc) = self.rtc.as_mut() {
+                    rtc.write(reg, data);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+impl SaveState for Mbc3 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.ram_timer_enable);
+        writer.u8(self.rom_bank);
+        writer.u8(self.ram_select);
+        writer.u8(self.latch);
+        if let Some(rtc) = self.rtc.as_ref() {
+            rtc.save_state(writer);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.ram_timer_enable = reader.bool()?;
+        self.rom_bank = reader.u8()?;
+        self.ram_select = reader.u8()?;
+        self.latch = reader.u8()?;
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.load_state(reader)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::rtc::tests::TestClock;
+    use crate::mbc::ROM_BANK_SIZE;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_rom_and_ram_banking() {
+        let mut rom = vec![0; 128 * ROM_BANK_SIZE];
+        rom[0x7F * ROM_BANK_SIZE] = 0x7F;
+        let mut ram = vec![0; 0x8000];
+        let mut mbc = Mbc3::new(None);
+        mbc.write_rom(0x2000, 0xFF);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x7F);
+
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_rom(0x4000, 0x03);
+        mbc.write_ram(&mut ram, 0xA001, 0x12);
+        assert_eq!(ram[0x6001], 0x12);
+        mbc.write_rom(0x4000, 0x08);
+        assert_eq!(mbc.read_ram(&ram, 0xA000), 0xFF);
+    }
+
+    #[test]
+    fn test_rtc_latch() {
+        let time = Rc::new(Cell::new(0));
+        let mut ram = Vec::new();
+        let mut mbc = Mbc3::new(Some(Box::new(TestClock(time.clone()))));
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_rom(0x4000, 0x08);
+
+        time.set(30);
+        mbc.write_rom(0x6000, 0x00);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_ram(&ram, 0xA000), 30);
+
+        // latched value holds until the next 0 -> 1 sequence
+        time.set(45);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_ram(&ram, 0xA000), 30);
+        mbc.write_rom(0x6000, 0x00);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_ram(&ram, 0xA000), 45);
+
+        mbc.write_ram(&mut ram, 0xA000, 5);
+        mbc.write_rom(0x6000, 0x00);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_ram(&ram, 0xA000), 5);
+    }
+}
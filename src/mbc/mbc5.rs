@@ -0,0 +1,111 @@
+use crate::mbc::{ram_offset, rom_offset};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Mbc5 {
+    pub ram_enable: bool,
+    pub rom_bank: u16, // 0x2000 - 0x3FFF, 9 bits
+    pub ram_bank: u8,  // 0x4000 - 0x5FFF, 4 bits
+    pub has_rumble: bool,
+    pub rumble: bool, // bit 3 of the ram bank register drives the motor on rumble carts
+}
+
+impl Mbc5 {
+    pub fn new(has_rumble: bool) -> Mbc5 {
+        Mbc5 {
+            rom_bank: 1,
+            has_rumble,
+            ..Default::default()
+        }
+    }
+
+    pub fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        let bank = match addr {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank as usize,
+        };
+        rom[rom_offset(rom, bank, addr)]
+    }
+
+    pub fn write_rom(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enable = data & 0x0F == 0x0A,
+            0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | data as u16,
+            0x3000..=0x3FFF => self.rom_bank = (self.rom_bank & 0xFF) | ((data as u16 & 1) << 8),
+            0x4000..=0x5FFF => {
+                if self.has_rumble {
+                    self.rumble = data & 0x08 != 0;
+                    self.ram_bank = data & 0x07;
+                } else {
+                    self.ram_bank = data & 0x0F;
+                }
+            }
+            0x6000..=0x7FFF => (),
+            _ => panic!("invalid mbc5 rom address: {:#x}", addr),
+        }
+    }
+
+    pub fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        if !self.ram_enable || ram.is_empty() {
+            return 0xFF;
+        }
+        ram[ram_offset(ram, self.ram_bank as usize, addr)]
+    }
+
+    pub fn write_ram(&mut self, ram: &mut [u8], addr: u16, data: u8) {
+        if !self.ram_enable || ram.is_empty() {
+            return;
+        }
+        ram[ram_offset(ram, self.ram_bank as usize, addr)] = data;
+    }
+}
+
+impl SaveState for Mbc5 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.ram_enable);
+        writer.u16(self.rom_bank);
+        writer.u8(self.ram_bank);
+        writer.bool(self.rumble);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enable = reader.bool()?;
+        self.rom_bank = reader.u16()?;
+        self.ram_bank = reader.u8()?;
+        self.rumble = reader.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::ROM_BANK_SIZE;
+
+    #[test]
+    fn test_nine_bit_rom_bank() {
+        let mut rom = vec![0; 512 * ROM_BANK_SIZE];
+        rom[0x1FF * ROM_BANK_SIZE] = 0x42;
+        let mut mbc = Mbc5::new(false);
+        mbc.write_rom(0x2000, 0xFF);
+        mbc.write_rom(0x3000, 0x01);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x42);
+        // unlike MBC1 and MBC3, bank 0 can be mapped at 0x4000
+        mbc.write_rom(0x2000, 0x00);
+        mbc.write_rom(0x3000, 0x00);
+        assert_eq!(mbc.rom_bank, 0);
+    }
+
+    #[test]
+    fn test_rumble_bit() {
+        let mut ram = vec![0; 0x20000];
+        let mut mbc = Mbc5::new(true);
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_rom(0x4000, 0x0B);
+        assert!(mbc.rumble);
+        mbc.write_ram(&mut ram, 0xA000, 0x12);
+        assert_eq!(ram[3 * 0x2000], 0x12);
+        mbc.write_rom(0x4000, 0x03);
+        assert!(!mbc.rumble);
+    }
+}
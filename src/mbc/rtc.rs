@@ -0,0 +1,305 @@
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+use byteorder::{ByteOrder, LittleEndian};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECONDS_PER_DAY: u64 = 60 * 60 * 24;
+const RTC_REGISTERS: [u8; 5] = [0x08, 0x09, 0x0A, 0x0B, 0x0C];
+pub const RTC_FOOTER_SIZE: usize = 48;
+
+// Source of host time for the cartridge clock, in whole seconds
+pub trait RtcClock {
+    fn now(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl RtcClock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RtcRegisters {
+    pub seconds: u8, // 0x08
+    pub minutes: u8, // 0x09
+    pub hours: u8,   // 0x0A
+    pub days: u16,   // 0x0B, low bit of 0x0C
+    pub halt: bool,
+    pub day_carry: bool,
+}
+
+impl RtcRegisters {
+    pub fn get(&self, reg: u8) -> u8 {
+        match reg {
+            0x08 => self.seconds,
+            0x09 => self.minutes,
+            0x0A => self.hours,
+            0x0B => self.days as u8,
+            0x0C => {
+                ((self.day_carry as u8) << 7) | ((self.halt as u8) << 6) | (self.days >> 8) as u8
+            }
+            _ => panic!("invalid rtc register: {:#x}", reg),
+        }
+    }
+
+    pub fn set(&mut self, reg: u8, value: u8) {
+        match reg {
+            0x08 => self.seconds = value & 0x3F,
+            0x09 => self.minutes = value & 0x3F,
+            0x0A => self.hours = value & 0x1F,
+            0x0B => self.days = (self.days & 0x100) | value as u16,
+            0x0C => {
+                self.days = (self.days & 0xFF) | (((value & 1) as u16) << 8);
+                self.halt = value & 0x40 != 0;
+                self.day_carry = value & 0x80 != 0;
+            }
+            _ => panic!("invalid rtc register: {:#x}", reg),
+        }
+    }
+
+    fn in_range(&self) -> bool {
+        self.seconds < 60 && self.minutes < 60 && self.hours < 24
+    }
+
+    // Out of range values count up to the register width and wrap without carrying
+    fn tick(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.days += 1;
+        if self.days == 512 {
+            self.days = 0;
+            self.day_carry = true;
+        }
+    }
+
+    fn advance(&mut self, mut elapsed: u64) {
+        while elapsed > 0 && !self.in_range() {
+            self.tick();
+            elapsed -= 1;
+        }
+        if elapsed == 0 {
+            return;
+        }
+        let total = self.seconds as u64
+            + self.minutes as u64 * 60
+            + self.hours as u64 * 3600
+            + self.days as u64 * SECONDS_PER_DAY
+            + elapsed;
+        let days = total / SECONDS_PER_DAY;
+        if days >= 512 {
+            self.day_carry = true;
+        }
+        self.days = (days % 512) as u16;
+        self.hours = (total % SECONDS_PER_DAY / 3600) as u8;
+        self.minutes = (total % 3600 / 60) as u8;
+        self.seconds = (total % 60) as u8;
+    }
+}
+
+pub struct Rtc {
+    pub live: RtcRegisters,
+    pub latched: RtcRegisters,
+    pub last_time: u64, // host time the live registers were last brought up to date
+    clock: Box<dyn RtcClock>,
+}
+
+impl Rtc {
+    pub fn new(clock: Box<dyn RtcClock>) -> Rtc {
+        Rtc {
+            live: RtcRegisters::default(),
+            latched: RtcRegisters::default(),
+            last_time: clock.now(),
+            clock,
+        }
+    }
+
+    pub fn set_clock(&mut self, clock: Box<dyn RtcClock>) {
+        self.update();
+        self.last_time = clock.now();
+        self.clock = clock;
+    }
+
+    // Catch the live registers up with the host clock, halted time is dropped
+    pub fn update(&mut self) {
+        let now = self.clock.now();
+        if !self.live.halt {
+            self.live.advance(now.saturating_sub(self.last_time));
+        }
+        self.last_time = now;
+    }
+
+    pub fn latch(&mut self) {
+        self.update();
+        self.latched = self.live;
+    }
+
+    pub fn read(&self, reg: u8) -> u8 {
+        self.latched.get(reg)
+    }
+
+    pub fn write(&mut self, reg: u8, value: u8) {
+        self.update();
+        self.live.set(reg, value);
+    }
+
+    // Footer appended to .sav files by VBA-M, BGB, SameBoy and friends: the live then
+    // latched registers as little endian u32s, followed by the u64 unix time they were saved at
+    pub fn to_footer(&self) -> [u8; RTC_FOOTER_SIZE] {
+        let mut footer = [0; RTC_FOOTER_SIZE];
+        for (i, reg) in RTC_REGISTERS.iter().enumerate() {
+            LittleEndian::write_u32(&mut footer[4 * i..4 * i + 4], self.live.get(*reg) as u32);
+            LittleEndian::write_u32(&mut footer[20 + 4 * i..24 + 4 * i], self.latched.get(*reg) as u32);
+        }
+        LittleEndian::write_u64(&mut footer[40..48], self.last_time);
+        footer
+    }
+
+    // Also accepts the older 44 byte footer with a 32 bit timestamp
+    pub fn load_footer(&mut self, footer: &[u8]) {
+        if footer.len() < 44 {
+            return;
+        }
+        for (i, reg) in RTC_REGISTERS.iter().enumerate() {
+            self.live.set(*reg, LittleEndian::read_u32(&footer[4 * i..4 * i + 4]) as u8);
+            self.latched.set(*reg, LittleEndian::read_u32(&footer[20 + 4 * i..24 + 4 * i]) as u8);
+        }
+        self.last_time = if footer.len() >= RTC_FOOTER_SIZE {
+            LittleEndian::read_u64(&footer[40..48])
+        } else {
+            LittleEndian::read_u32(&footer[40..44]) as u64
+        };
+        self.update();
+    }
+}
+
+impl SaveState for RtcRegisters {
+    fn save_state(&self, writer: &mut StateWriter) {
+        for reg in RTC_REGISTERS.iter() {
+            writer.u8(self.get(*reg));
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        for reg in RTC_REGISTERS.iter() {
+            self.set(*reg, reader.u8()?);
+        }
+        Ok(())
+    }
+}
+
+impl SaveState for Rtc {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.live.save_state(writer);
+        self.latched.save_state(writer);
+        writer.u64(self.last_time);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.live.load_state(reader)?;
+        self.latched.load_state(reader)?;
+        self.last_time = reader.u64()?;
+        self.update();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    pub struct TestClock(pub Rc<Cell<u64>>);
+
+    impl RtcClock for TestClock {
+        fn now(&self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    pub fn test_rtc() -> (Rtc, Rc<Cell<u64>>) {
+        let time = Rc::new(Cell::new(1000));
+        (Rtc::new(Box::new(TestClock(time.clone()))), time)
+    }
+
+    #[test]
+    fn test_advance() {
+        let (mut rtc, time) = test_rtc();
+        time.set(1000 + 2 * SECONDS_PER_DAY + 3 * 3600 + 4 * 60 + 5);
+        rtc.latch();
+        assert_eq!(rtc.read(0x08), 5);
+        assert_eq!(rtc.read(0x09), 4);
+        assert_eq!(rtc.read(0x0A), 3);
+        assert_eq!(rtc.read(0x0B), 2);
+        assert_eq!(rtc.read(0x0C), 0);
+    }
+
+    #[test]
+    fn test_day_carry() {
+        let (mut rtc, time) = test_rtc();
+        rtc.write(0x0B, 0xFF);
+        rtc.write(0x0C, 0x01);
+        time.set(1000 + SECONDS_PER_DAY);
+        rtc.latch();
+        assert_eq!(rtc.read(0x0B), 0);
+        assert_eq!(rtc.read(0x0C), 0x80);
+    }
+
+    #[test]
+    fn test_halt() {
+        let (mut rtc, time) = test_rtc();
+        rtc.write(0x0C, 0x40);
+        time.set(2000);
+        rtc.latch();
+        assert_eq!(rtc.read(0x08), 0);
+        rtc.write(0x0C, 0x00);
+        time.set(2010);
+        rtc.latch();
+        assert_eq!(rtc.read(0x08), 10);
+    }
+
+    #[test]
+    fn test_footer_round_trip() {
+        let (mut rtc, time) = test_rtc();
+        time.set(1000 + 3600 + 7);
+        rtc.latch();
+        let footer = rtc.to_footer();
+        assert_eq!(footer[8], 1);
+        assert_eq!(footer[20], 7);
+
+        // time keeps passing while the emulator is closed
+        let (mut loaded, time) = test_rtc();
+        time.set(1000 + 3600 + 7 + 60);
+        loaded.load_footer(&footer);
+        assert_eq!(loaded.latched, rtc.latched);
+        assert_eq!(loaded.live.minutes, 1);
+        assert_eq!(loaded.live.hours, 1);
+    }
+
+    #[test]
+    fn test_out_of_range_seconds_wrap_without_carry() {
+        let (mut rtc, time) = test_rtc();
+        rtc.write(0x08, 62);
+        time.set(1002);
+        rtc.latch();
+        assert_eq!(rtc.read(0x08), 0);
+        assert_eq!(rtc.read(0x09), 0);
+    }
+}
@@ -1,6 +1,7 @@
-use crate::register_maps::IORegisters;
-use sdl2::pixels::Color;
-use std::convert::TryInto;
+use crate::register_maps::{IORegisters, Interrupt};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+use std::collections::VecDeque;
+use std::convert::{TryFrom, TryInto};
 use std::ops::{Deref, DerefMut};
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -11,18 +12,42 @@ pub enum GameboyColor {
     DarkGray,
     Black,
 }
-impl Into<Color> for GameboyColor {
-    fn into(self) -> Color {
-        match self {
-            GameboyColor::White => Color::RGB(155, 188, 15),
-            GameboyColor::LightGray => Color::RGB(139, 172, 15),
-            GameboyColor::DarkGray => Color::RGB(48, 98, 48),
-            GameboyColor::Black => Color::RGB(15, 56, 15),
-            GameboyColor::Transparent => Color::RGB(15, 56, 15),
+
+// ARGB8888 shades for white, light gray, dark gray and black
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Palette(pub [u32; 4]);
+
+impl Palette {
+    pub const GREEN: Palette = Palette([0xFF9BBC0F, 0xFF8BAC0F, 0xFF306230, 0xFF0F380F]);
+    pub const GRAY: Palette = Palette([0xFFFFFFFF, 0xFFAAAAAA, 0xFF555555, 0xFF000000]);
+    pub const POCKET: Palette = Palette([0xFFC4CFA1, 0xFF8B956D, 0xFF4D533C, 0xFF1F1F1F]);
+    pub const NAMES: [&'static str; 3] = ["green", "gray", "pocket"];
+
+    pub fn from_name(name: &str) -> Option<Palette> {
+        match name {
+            "green" => Some(Palette::GREEN),
+            "gray" | "grey" => Some(Palette::GRAY),
+            "pocket" => Some(Palette::POCKET),
+            _ => None,
+        }
+    }
+
+    pub fn color(&self, color: GameboyColor) -> u32 {
+        match color {
+            GameboyColor::White => self.0[0],
+            GameboyColor::LightGray => self.0[1],
+            GameboyColor::DarkGray => self.0[2],
+            GameboyColor::Black | GameboyColor::Transparent => self.0[3],
         }
     }
 }
 
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::GREEN
+    }
+}
+
 impl From<&GameboyColor> for u32 {
     fn from(color: &GameboyColor) -> u32 {
         match color {
@@ -44,6 +69,8 @@ pub struct OamEntry {
     y_flip: bool,
     x_flip: bool,
     palette: bool,
+    vram_bank: bool, // cgb only
+    cgb_palette: u8, // cgb only
 }
 
 impl From<[u8; 4]> for OamEntry {
@@ -52,33 +79,69 @@ impl From<[u8; 4]> for OamEntry {
             y_coord: bytes[0],
             x_coord: bytes[1],
             data_tile_num: bytes[2],
-            sprite_priority: !(bytes[3] & 8 == 0),
-            y_flip: !(bytes[3] & 4 == 0),
-            x_flip: !(bytes[3] & 2 == 0),
-            palette: !(bytes[3] & 1 == 0),
+            sprite_priority: check_bit(bytes[3], 7),
+            y_flip: check_bit(bytes[3], 6),
+            x_flip: check_bit(bytes[3], 5),
+            palette: check_bit(bytes[3], 4),
+            vram_bank: check_bit(bytes[3], 3),
+            cgb_palette: bytes[3] & 0x07,
         }
     }
 }
 
 impl From<&OamEntry> for [u8; 4] {
     fn from(entry: &OamEntry) -> Self {
-        let mut byte_4: u8 = 0;
+        let mut byte_4: u8 = entry.cgb_palette;
         if entry.sprite_priority {
-            byte_4 |= 8;
+            byte_4 |= 0x80;
         }
         if entry.y_flip {
-            byte_4 |= 4;
+            byte_4 |= 0x40;
         }
         if entry.x_flip {
-            byte_4 |= 2;
+            byte_4 |= 0x20;
         }
         if entry.palette {
-            byte_4 |= 1;
+            byte_4 |= 0x10;
+        }
+        if entry.vram_bank {
+            byte_4 |= 0x08;
         }
         [entry.y_coord, entry.x_coord, entry.data_tile_num, byte_4]
     }
 }
 
+// Background map attributes, stored in vram bank 1 at the same index as the tile number
+#[derive(Copy, Clone, Debug, Default)]
+struct TileAttributes {
+    palette: usize,
+    bank: usize,
+    x_flip: bool,
+    y_flip: bool,
+    priority: bool, // background colors 1 - 3 cover all objects
+}
+
+impl From<u8> for TileAttributes {
+    fn from(byte: u8) -> Self {
+        TileAttributes {
+            palette: (byte & 0x07) as usize,
+            bank: ((byte >> 3) & 1) as usize,
+            x_flip: check_bit(byte, 5),
+            y_flip: check_bit(byte, 6),
+            priority: check_bit(byte, 7),
+        }
+    }
+}
+
+// ARGB8888 from a cgb palette entry, each 5 bit channel is widened to 8 bits
+pub fn rgb555_to_argb(color: u16) -> u32 {
+    let widen = |channel: u16| {
+        let channel = (channel & 0x1F) as u32;
+        (channel << 3) | (channel >> 2)
+    };
+    0xFF << 24 | widen(color) << 16 | widen(color >> 5) << 8 | widen(color >> 10)
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct OAM {
     oam_entries: [OamEntry; 40],
@@ -156,209 +219,468 @@ impl DerefMut for Tile {
     }
 }
 
-#[derive(Clone, Copy)]
+const LINE_WIDTH: usize = 160;
+const SCREEN_HEIGHT: usize = 144;
+const SPRITE_FETCH_DOTS: u8 = 6;
+const MODE2_DOTS: u16 = 80;
+const LINE_DOTS: u16 = 456;
+const LINES: u8 = 154;
+
+// A finished pixel of a scanline. Dmg shades still go through the shade palette or
+// the sgb, cgb colors are final
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Pixel {
+    Shade(GameboyColor),
+    Color(u16), // RGB555
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct FifoPixel {
+    color: u8,         // color number from the tile data
+    palette: u8,       // cgb palette, or obp1 for dmg objects
+    bg_priority: bool, // background attribute or object flag asking for the background on top
+    oam_index: u8,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum FetcherStep {
+    Tile,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+// Mode 3 state. The fetcher reads a tile row into the background fifo every 6 dots while
+// a pixel a dot is shifted out to the line, an object stops both for its own fetch. The
+// registers are read as the pixels go, so changes in the middle of a line show up
+#[derive(Clone, Debug)]
+pub struct PixelFifo {
+    bg: VecDeque<FifoPixel>,
+    obj: VecDeque<FifoPixel>,
+    step: FetcherStep,
+    step_dots: u8,
+    first_fetch: bool,
+    tile_x: u8, // tiles fetched on this line, or in the window
+    tile_number: u8,
+    attributes: TileAttributes,
+    data_low: u8,
+    data_high: u8,
+    in_window: bool,
+    window_x: Option<u8>, // where the window last started on this line
+    discard: u8, // scx % 8 pixels scrolled off the left edge
+    lcd_x: u8,
+    sprites: Vec<(u8, OamEntry)>, // not fetched yet, with their oam index
+    sprite_fetch: Option<(u8, OamEntry, u8)>, // dots left in the last element
+    pub dots: u16, // length of mode 3 so far
+    pub line: Vec<Pixel>,
+}
+
+impl Default for PixelFifo {
+    fn default() -> Self {
+        PixelFifo {
+            bg: VecDeque::with_capacity(16),
+            obj: VecDeque::with_capacity(8),
+            step: FetcherStep::Tile,
+            step_dots: 0,
+            first_fetch: true,
+            tile_x: 0,
+            tile_number: 0,
+            attributes: TileAttributes::default(),
+            data_low: 0,
+            data_high: 0,
+            in_window: false,
+            window_x: None,
+            discard: 0,
+            lcd_x: 0,
+            sprites: Vec::new(),
+            sprite_fetch: None,
+            dots: 0,
+            line: Vec::with_capacity(LINE_WIDTH),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct PPU {
-    pub vram: [u8; 0x2000],
+    pub vram: [u8; 0x4000], // bank 1 follows bank 0, only used on cgb
     pub oam: OAM,
     pub io_registers: IORegisters,
     pub mode: Mode,
+    pub fifo: PixelFifo,
+    line: u8,               // the line being drawn, ly differs from it on line 153
+    line_dots: u16,         // dots into the current line
+    window_y_hit: bool,     // ly matched wy at some point this frame
+    window_line: u8,        // window rows drawn so far this frame
+    pub screen: Vec<Pixel>, // finished lines of the current frame
+    pub frame_ready: bool,  // set when line 153 ends, cleared by whoever shows the frame
 }
 
 impl PPU {
     pub fn new(io_registers: IORegisters) -> PPU {
         PPU {
-            vram: [0; 0x2000],
+            vram: [0; 0x4000],
             oam: OAM { oam_entries: [OamEntry::default(); 40].into()},
             io_registers,
             mode: Mode::Off,
+            fifo: PixelFifo::default(),
+            line: 0,
+            line_dots: 0,
+            window_y_hit: false,
+            window_line: 0,
+            screen: vec![Pixel::Shade(GameboyColor::White); LINE_WIDTH * SCREEN_HEIGHT],
+            frame_ready: false,
         }
     }
 
-    pub fn object_search(&self) -> Vec<OamEntry> {
-        let scanline = self.io_registers.ly;
-        let mut oam_vec: Vec<OamEntry> = Vec::new();
-        let use_16 = self.io_registers.lcdc.obj_size == 1;
-        for s in 0..40 {
-            let new_entry = self.oam.oam_entries[s];
-            oam_vec.push(new_entry);
-        }
-        let offset = if use_16 { 16 } else { 8 };
-        oam_vec = oam_vec
-            .into_iter()
-            .filter(|oam_entry| {
-                scanline + 16 >= oam_entry.y_coord && scanline + 16 < oam_entry.y_coord + offset
-            })
-            .take(10)
-            .collect::<Vec<OamEntry>>();
-        oam_vec.sort_by_key(|o| o.x_coord);
-        oam_vec.reverse();
-        oam_vec
+    // Index into vram for a cpu access to 0x8000 - 0x9FFF, through the bank in VBK
+    pub fn vram_offset(&self, addr: usize) -> usize {
+        (self.io_registers.vbk as usize & 1) * 0x2000 + addr - 0x8000
     }
 
-    pub fn draw(&self, sprites: Vec<OamEntry>) -> Vec<GameboyColor> {
-        let scanline = self.io_registers.ly;
-        let lcdc = self.io_registers.lcdc;
-        let palette = self.io_registers.bgp;
-        let mut color_line: Vec<GameboyColor> = Vec::new();
-	for x in 0..=20 {
-	    let use_window = lcdc.window_enable == 1
-		&& scanline >= self.io_registers.wy
-		&& x + 7 >= self.io_registers.wx;
-	    //if lcdc.window_enable == 1 { println!("window"); }
-	    let tile_index;
-	    let pixel_y;
-	    let tile_x;
-	    let tile_y;
-	    if use_window {
-		println!("windowww!");
-		let pixel_x = x + 7 - self.io_registers.wx;
-		pixel_y = scanline - self.io_registers.wy;
-		tile_x = pixel_x / 8;
-		tile_y = pixel_y / 8;
-		let window_map_index = tile_x as usize + 32 * tile_y as usize;
-		let base_pointer = if lcdc.window_tile_map_area == 0 {
-		    0x1800
-		} else {
-		    0x1C00
-		};
-		tile_index = self.vram[base_pointer + window_map_index];
-	    } else {
-		pixel_y = self.io_registers.scy.wrapping_add(scanline);
-		tile_y = pixel_y / 8;
-		tile_x = (self.io_registers.scx / 8 + x) & 0x1F;
-		let background_map_index = tile_x as usize + 32 * tile_y as usize;
-		let base_pointer = if lcdc.bg_tile_map_area == 0 {
-		    0x1800
-		} else {
-		    0x1C00
-		};
-		tile_index = self.vram[base_pointer + background_map_index];
-		//assert!(tile_index == self.get_tile(x), "new: {}, old: {}", tile_index, self.get_tile(x));
-	    }
-	    let mem_tile_index = if lcdc.bg_window_tile_data_area == 0 {
-		((0x1000 as isize)
-		 + ((tile_index as i8 as isize) * 16)
-		 + (pixel_y % 8) as isize * 2) as usize
-	    } else {
-		(tile_index as usize * 16) + ((pixel_y % 8) as usize) * 2
-	    };
-	    if lcdc.bg_window_tile_data_area == 0 {
-		//println!("other");
-		assert!(mem_tile_index >= 0x800 && mem_tile_index <= 0x17FF, "mem_tile_index: {}", mem_tile_index)
-	    }
-		
-	    Self::get_color_line(&mut color_line, self.vram[mem_tile_index + 1], self.vram[mem_tile_index], palette, false);
-	}
-	color_line.drain(0..(self.io_registers.scx % 8) as usize);
-	color_line.drain(160..);
+    // Advances the lcd by some dots, four per machine cycle in normal speed. Returns
+    // true when an h-blank began, for h-blank dma
+    pub fn tick(&mut self, dots: u8) -> bool {
+        if self.io_registers.lcdc.lcd_ppu_enable == 0 {
+            if self.mode != Mode::Off {
+                self.mode = Mode::Off;
+                self.line = 0;
+                self.io_registers.ly = 0;
+                self.io_registers.lcd_status.mode = 0;
+                self.line_dots = 0;
+                self.io_registers.update_stat_line();
+            }
+            return false;
+        }
+        if self.mode == Mode::Off {
+            self.line_dots = 0;
+            self.line = 0;
+            self.window_y_hit = false;
+            self.window_line = 0;
+            self.io_registers.ly = 0;
+            self.set_mode(Mode::Mode2);
+        }
 
-        for sprite in sprites {
-            let tile_height = if lcdc.obj_size == 1 { 16 } else { 8 };
-            let tile_row = if sprite.y_flip {
-                tile_height - (scanline - (sprite.y_coord - 16))
-            } else {
-                scanline - (sprite.y_coord - 16)
-            };
-            let tile_low =
-                self.vram[(sprite.data_tile_num as usize * 16 + 2 * tile_row as usize) as usize];
-            let tile_high = self.vram
-                [(sprite.data_tile_num as usize * 16 + 2 * tile_row as usize + 1) as usize];
-            let sprite_palette = if sprite.palette {
-                self.io_registers.obp1
-            } else {
-                self.io_registers.obp0
-            };
-            let mut sprite_row: Vec<GameboyColor> = Vec::new();
-            PPU::get_color_line(&mut sprite_row, tile_high, tile_low, sprite_palette, true);
-            if sprite.x_flip {
-                sprite_row.reverse()
-            };
-            for i in 0..8 {
-                if sprite.x_coord + i >= 168 || sprite.x_coord + i < 8 {
-                    continue;
+        let mut hblank = false;
+        for _ in 0..dots {
+            self.line_dots += 1;
+            match self.mode {
+                Mode::Mode2 if self.line_dots == MODE2_DOTS => {
+                    if self.io_registers.ly == self.io_registers.wy {
+                        self.window_y_hit = true;
+                    }
+                    let sprites = self.object_search();
+                    self.start_line(sprites);
+                    self.set_mode(Mode::Mode3);
                 }
-                if (!sprite.sprite_priority
-                    || color_line[(sprite.x_coord + i - 8) as usize] == GameboyColor::White)
-		    && sprite_row[i as usize] != GameboyColor::Transparent
-                {
-                    color_line[(sprite.x_coord + i - 8) as usize] = sprite_row[i as usize];
+                Mode::Mode3 => {
+                    if self.step_dot() {
+                        let begin_index = self.line as usize * LINE_WIDTH;
+                        self.screen[begin_index..begin_index + LINE_WIDTH].copy_from_slice(&self.fifo.line);
+                        self.set_mode(Mode::Mode0);
+                        hblank = true;
+                    }
                 }
+                Mode::Mode0 | Mode::Mode1 if self.line_dots == LINE_DOTS => self.next_line(),
+                // ly already reads 0 one machine cycle into the last line, so LYC=0
+                // matches there and not again at the start of line 0
+                Mode::Mode1 if self.line == LINES - 1 && self.line_dots == 4 => self.io_registers.ly = 0,
+                _ => (),
             }
+            self.compare_lyc();
+            self.io_registers.update_stat_line();
         }
-        color_line
+        hblank
     }
 
-    fn get_tile(&self, x: u8) -> u8 {
-        let cur_scanline = self.io_registers.ly;
-        let lcdc = self.io_registers.lcdc;
-        let window_y = self.io_registers.wy;
-        let window_x = self.io_registers.wx;
-        let scroll_y = self.io_registers.scy;
-        let scroll_x = self.io_registers.scx;
-
-        let use_window = cur_scanline >= window_y
-            && x + 7 >= window_x / 8
-            && x + 7 <= ((window_x as u16 + 160) / 8) as u8
-            && lcdc.window_enable == 1;
-
-        let fetcher_y = if use_window {
-            cur_scanline - window_y
-        } else {
-            ((cur_scanline as u16 + scroll_y as u16) & 255) as u8
-        };
+    fn next_line(&mut self) {
+        if self.mode == Mode::Mode0 && self.fifo.window_x.is_some() {
+            self.window_line += 1;
+        }
+        self.line_dots = 0;
+        self.line += 1;
+        if self.line == LINES {
+            self.line = 0;
+            self.frame_ready = true;
+            self.window_y_hit = false;
+            self.window_line = 0;
+        }
+        self.io_registers.ly = self.line;
+        let line = self.line as usize;
+        if line < SCREEN_HEIGHT {
+            self.set_mode(Mode::Mode2);
+        } else if line == SCREEN_HEIGHT {
+            self.io_registers.interrupt_flag.request(Interrupt::VBlank);
+            self.set_mode(Mode::Mode1);
+        }
+    }
 
-        let fetcher_x = if use_window {
-            x * 8 - window_x + 7
-        } else {
-            (scroll_x / 8 + x) & 0x1F
+    fn compare_lyc(&mut self) {
+        let io_registers = &mut self.io_registers;
+        io_registers.lcd_status.lyc_eq_ly = (io_registers.ly == io_registers.lyc) as u8;
+    }
+
+    // The stat interrupt follows from the mode through IORegisters::update_stat_line
+    fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+        self.io_registers.lcd_status.mode = u8::from(mode) & 0x03;
+    }
+
+    // The first ten objects on this line in oam order. The search runs with objects
+    // disabled too, lcdc bit 1 only stops the fifo from fetching them
+    pub fn object_search(&self) -> Vec<(u8, OamEntry)> {
+        // objects near y = 255 are off screen, the bottom would overflow in u8
+        let y = self.io_registers.ly as u16 + 16;
+        let use_16 = self.io_registers.lcdc.obj_size == 1;
+        let offset = if use_16 { 16 } else { 8 };
+        self.oam
+            .oam_entries
+            .iter()
+            .enumerate()
+            .filter(|(_, oam_entry)| {
+                let top = oam_entry.y_coord as u16;
+                y >= top && y < top + offset
+            })
+            .take(10)
+            .map(|(index, oam_entry)| (index as u8, *oam_entry))
+            .collect()
+    }
+
+    // Mode 3 begins, with the objects object_search found during mode 2
+    pub fn start_line(&mut self, sprites: Vec<(u8, OamEntry)>) {
+        self.fifo = PixelFifo {
+            sprites,
+            discard: self.io_registers.scx % 8,
+            ..PixelFifo::default()
         };
+    }
 
-        let tilemap_base: u16 = if use_window {
-            if lcdc.window_tile_map_area == 1 {
-                0x1C00
+    // One dot of mode 3, true once the last pixel of the line is out
+    pub fn step_dot(&mut self) -> bool {
+        self.fifo.dots += 1;
+        if let Some((index, sprite, dots)) = self.fifo.sprite_fetch {
+            if dots > 1 {
+                self.fifo.sprite_fetch = Some((index, sprite, dots - 1));
             } else {
-                0x1800
+                self.fifo.sprite_fetch = None;
+                self.fetch_sprite(index, sprite);
             }
-        } else {
-            if lcdc.bg_tile_map_area == 1 {
-                0x1C00
+            return false;
+        }
+
+        // Objects are fetched lowest x first and by oam index among equal x, so the one
+        // fetched first keeps its pixels. With lcdc bit 1 clear the ones reached are
+        // dropped, setting it again later in the line does not bring them back
+        let lcd_x = self.fifo.lcd_x;
+        if !self.fifo.bg.is_empty() && self.fifo.discard == 0 && self.io_registers.lcdc.obj_enable == 0 {
+            self.fifo.sprites.retain(|(_, sprite)| sprite.x_coord > lcd_x + 8);
+        } else if !self.fifo.bg.is_empty() && self.fifo.discard == 0 {
+            let next_sprite = self
+                .fifo
+                .sprites
+                .iter()
+                .enumerate()
+                .filter(|(_, (_, sprite))| sprite.x_coord <= lcd_x + 8)
+                .min_by_key(|(_, (index, sprite))| (sprite.x_coord, *index))
+                .map(|(i, _)| i);
+            if let Some(i) = next_sprite {
+                // this dot is the first of the fetch
+                let (index, sprite) = self.fifo.sprites.remove(i);
+                self.fifo.sprite_fetch = Some((index, sprite, SPRITE_FETCH_DOTS - 1));
+                return false;
+            }
+        }
+
+        // The window starts where lcd x + 7 meets wx, once ly has matched wy this frame.
+        // Moving wx further along the line starts it over from its first tile
+        let wx = self.io_registers.wx;
+        if self.window_y_hit
+            && self.io_registers.lcdc.window_enable == 1
+            && !self.fifo.first_fetch
+            && lcd_x == wx.saturating_sub(7)
+            && self.fifo.window_x != Some(lcd_x)
+        {
+            // the fetcher starts over on the window, fine scrolling is for the background
+            // only. Below 7 the window begins off the left edge
+            self.fifo.in_window = true;
+            self.fifo.window_x = Some(lcd_x);
+            self.fifo.bg.clear();
+            self.fifo.discard = 7_u8.saturating_sub(wx);
+            self.fifo.tile_x = 0;
+            self.fifo.step = FetcherStep::Tile;
+            self.fifo.step_dots = 0;
+        }
+
+        if let Some(pixel) = self.fifo.bg.pop_front() {
+            if self.fifo.discard > 0 {
+                self.fifo.discard -= 1;
             } else {
-                0x1800
+                let obj = self.fifo.obj.pop_front();
+                let pixel = self.mix_pixel(pixel, obj);
+                self.fifo.line.push(pixel);
+                self.fifo.lcd_x += 1;
             }
-        };
-        let vram_index = tilemap_base + ((fetcher_y as u16 / 8 * 32) + fetcher_x as u16) as u16;
-        self.vram[vram_index as usize]
+        }
+        self.step_fetcher();
+        self.fifo.lcd_x as usize == LINE_WIDTH
     }
 
-    fn get_tile_data_low(&self, tile_index: u8) -> u8 {
-        let cur_scanline = self.io_registers.ly;
-        let lcdc = self.io_registers.lcdc;
-        let tile_row = (cur_scanline % 8) as usize;
+    // Each step but the push takes two dots, the push waits for an empty fifo
+    fn step_fetcher(&mut self) {
+        if self.fifo.step != FetcherStep::Push {
+            self.fifo.step_dots += 1;
+            if self.fifo.step_dots < 2 {
+                return;
+            }
+            self.fifo.step_dots = 0;
+        }
+        match self.fifo.step {
+            FetcherStep::Tile => {
+                let map_index = self.tile_map_index();
+                self.fifo.tile_number = self.vram[map_index];
+                self.fifo.attributes = if self.io_registers.cgb_mode {
+                    TileAttributes::from(self.vram[0x2000 + map_index])
+                } else {
+                    TileAttributes::default()
+                };
+                self.fifo.step = FetcherStep::DataLow;
+            }
+            FetcherStep::DataLow => {
+                self.fifo.data_low = self.vram[self.tile_data_index()];
+                self.fifo.step = FetcherStep::DataHigh;
+            }
+            FetcherStep::DataHigh => {
+                self.fifo.data_high = self.vram[self.tile_data_index() + 1];
+                self.fifo.step = FetcherStep::Push;
+                self.push_tile();
+            }
+            FetcherStep::Push => self.push_tile(),
+        }
+    }
 
-        let index = if lcdc.bg_window_tile_data_area == 0 {
-            (0x1000 as usize)
-                .wrapping_add(((tile_index as i8 as isize) * 16) as usize)
-                .wrapping_add(tile_row * 2)
-        } else {
-            (tile_index as usize) * 16 + tile_row * 2
-        };
+    fn push_tile(&mut self) {
+        let fifo = &mut self.fifo;
+        if !fifo.bg.is_empty() {
+            return;
+        }
+        fifo.step = FetcherStep::Tile;
+        if fifo.first_fetch {
+            // the first fetch of every line is thrown away
+            fifo.first_fetch = false;
+            return;
+        }
+        let mut color_numbers = Self::color_numbers(fifo.data_high, fifo.data_low);
+        if fifo.attributes.x_flip {
+            color_numbers.reverse();
+        }
+        let attributes = fifo.attributes;
+        fifo.bg.extend(color_numbers.iter().map(|color| FifoPixel {
+            color: *color,
+            palette: attributes.palette as u8,
+            bg_priority: attributes.priority,
+            oam_index: 0,
+        }));
+        fifo.tile_x += 1;
+    }
 
-        self.vram[index]
+    // Row of the window or background the fetcher is on
+    fn fetcher_y(&self) -> u8 {
+        let io_registers = &self.io_registers;
+        if self.fifo.in_window {
+            self.window_line
+        } else {
+            io_registers.scy.wrapping_add(io_registers.ly)
+        }
     }
 
-    fn get_tile_data_high(&self, tile_index: u8) -> u8 {
-        let cur_scanline = self.io_registers.ly;
+    fn tile_map_index(&self) -> usize {
         let lcdc = self.io_registers.lcdc;
-        let tile_row = (cur_scanline % 8) as usize;
-        let index = if lcdc.bg_window_tile_data_area == 0 {
-            (0x1000 as usize)
-                .wrapping_add(((tile_index as i8 as isize) * 16) as usize)
-                .wrapping_add(tile_row * 2)
+        let (map_area, tile_x) = if self.fifo.in_window {
+            (lcdc.window_tile_map_area, self.fifo.tile_x)
+        } else {
+            (lcdc.bg_tile_map_area, self.io_registers.scx / 8 + self.fifo.tile_x)
+        };
+        let base_pointer = if map_area == 0 { 0x1800 } else { 0x1C00 };
+        base_pointer + (tile_x & 0x1F) as usize + 32 * (self.fetcher_y() / 8) as usize
+    }
+
+    fn tile_data_index(&self) -> usize {
+        let pixel_y = self.fetcher_y() % 8;
+        let attributes = self.fifo.attributes;
+        let tile_row = if attributes.y_flip { 7 - pixel_y } else { pixel_y } as usize;
+        let tile_number = self.fifo.tile_number;
+        let tile_start = if self.io_registers.lcdc.bg_window_tile_data_area == 0 {
+            (0x1000 + tile_number as i8 as isize * 16) as usize
         } else {
-            let tile_index = tile_index as usize;
-            (tile_index as usize) * 16 + tile_row * 2
+            tile_number as usize * 16
         };
-        self.vram[index + 1]
+        attributes.bank * 0x2000 + tile_start + tile_row * 2
+    }
+
+    // Objects mix into the object fifo, an object already there keeps its pixels unless
+    // the cgb gives the new one priority through its lower oam index
+    fn fetch_sprite(&mut self, index: u8, sprite: OamEntry) {
+        let io_registers = &self.io_registers;
+        let cgb_mode = io_registers.cgb_mode;
+        let tile_height = if io_registers.lcdc.obj_size == 1 { 16 } else { 8 };
+        let tile_num = if tile_height == 16 { sprite.data_tile_num & 0xFE } else { sprite.data_tile_num };
+        let row = (io_registers.ly + 16).wrapping_sub(sprite.y_coord) & (tile_height - 1);
+        let tile_row = if sprite.y_flip { tile_height - 1 - row } else { row };
+        let bank = if cgb_mode && sprite.vram_bank { 0x2000 } else { 0 };
+        let tile_index = bank + tile_num as usize * 16 + 2 * tile_row as usize;
+        let mut color_numbers = Self::color_numbers(self.vram[tile_index + 1], self.vram[tile_index]);
+        if sprite.x_flip {
+            color_numbers.reverse();
+        }
+
+        // objects hanging over the left edge lose their first pixels
+        let skip = (self.fifo.lcd_x + 8).saturating_sub(sprite.x_coord) as usize;
+        if skip >= 8 {
+            return;
+        }
+        let obj = &mut self.fifo.obj;
+        while obj.len() < 8 - skip {
+            obj.push_back(FifoPixel::default());
+        }
+        for (slot, color) in obj.iter_mut().zip(color_numbers[skip..].iter()) {
+            if *color != 0 && (slot.color == 0 || (cgb_mode && index < slot.oam_index)) {
+                *slot = FifoPixel {
+                    color: *color,
+                    palette: if cgb_mode { sprite.cgb_palette } else { sprite.palette as u8 },
+                    bg_priority: sprite.sprite_priority,
+                    oam_index: index,
+                };
+            }
+        }
+    }
+
+    fn mix_pixel(&self, bg: FifoPixel, obj: Option<FifoPixel>) -> Pixel {
+        let io_registers = &self.io_registers;
+        let lcdc = io_registers.lcdc;
+        let obj = obj.filter(|obj| obj.color != 0 && lcdc.obj_enable == 1);
+        if io_registers.cgb_mode {
+            // with lcdc bit 0 clear objects always win, otherwise either priority bit
+            // hides them behind background colors 1 - 3
+            let behind_bg = |obj: &FifoPixel| {
+                lcdc.bg_window_enable_priority == 1 && bg.color != 0 && (bg.bg_priority || obj.bg_priority)
+            };
+            return match obj {
+                Some(obj) if !behind_bg(&obj) => {
+                    Pixel::Color(io_registers.obj_palettes.color(obj.palette as usize, obj.color as usize))
+                }
+                _ => Pixel::Color(io_registers.bg_palettes.color(bg.palette as usize, bg.color as usize)),
+            };
+        }
+        // lcdc bit 0 blanks background and window on the dmg
+        let bg_color = if lcdc.bg_window_enable_priority == 0 { 0 } else { bg.color };
+        let bg_shade = PPU::get_color(bg_color, io_registers.bgp, false);
+        match obj {
+            // the priority flag goes by the color number, not the shade bgp gives it
+            Some(obj) if !obj.bg_priority || bg_color == 0 => {
+                let palette = if obj.palette == 1 { io_registers.obp1 } else { io_registers.obp0 };
+                Pixel::Shade(PPU::get_color(obj.color, palette, true))
+            }
+            _ => Pixel::Shade(bg_shade),
+        }
     }
 
     fn get_color_line(
@@ -420,6 +742,16 @@ impl PPU {
         }
     }
 
+    // Raw color numbers of a tile row, leftmost pixel first
+    fn color_numbers(high_byte: u8, low_byte: u8) -> [u8; 8] {
+        let mut numbers = [0; 8];
+        for (i, number) in numbers.iter_mut().enumerate() {
+            let bit = 7 - i;
+            *number = (((high_byte >> bit) & 1) << 1) | ((low_byte >> bit) & 1);
+        }
+        numbers
+    }
+
     fn get_color(color_number: u8, palette: u8, is_sprite: bool) -> GameboyColor {
         let color_id = match color_number {
             0 => if is_sprite {
@@ -442,11 +774,48 @@ impl PPU {
     }
 }
 
+impl SaveState for PPU {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bytes(&self.vram);
+        writer.bytes(&<[u8; 0xA0]>::from(self.oam));
+        self.io_registers.save_state(writer);
+        writer.u8(self.mode.into());
+        writer.u8(self.line);
+        writer.u16(self.line_dots);
+        writer.bool(self.window_y_hit);
+        writer.u8(self.window_line);
+        writer.bool(self.frame_ready);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.bytes(&mut self.vram)?;
+        let mut oam = [0; 0xA0];
+        reader.bytes(&mut oam)?;
+        self.oam = oam.into();
+        self.io_registers.load_state(reader)?;
+        self.mode = Mode::try_from(reader.u8()?)?;
+        self.line = reader.u8()?;
+        self.line_dots = reader.u16()?;
+        if self.line >= LINES || self.line_dots >= LINE_DOTS {
+            return Err(StateError::InvalidValue("ppu line position"));
+        }
+        self.window_y_hit = reader.bool()?;
+        self.window_line = reader.u8()?;
+        self.frame_ready = reader.bool()?;
+        if self.mode == Mode::Mode3 {
+            // the fifo is not saved, the line starts over and ends a little late
+            let sprites = self.object_search();
+            self.start_line(sprites);
+        }
+        Ok(())
+    }
+}
+
 fn check_bit(num: u8, bit_num: u8) -> bool {
     !(num & (1 << bit_num) == 0)
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Mode {
     Mode0,
     Mode1,
@@ -454,3 +823,30 @@ pub enum Mode {
     Mode3,
     Off,
 }
+
+impl From<Mode> for u8 {
+    fn from(mode: Mode) -> u8 {
+        match mode {
+            Mode::Mode0 => 0,
+            Mode::Mode1 => 1,
+            Mode::Mode2 => 2,
+            Mode::Mode3 => 3,
+            Mode::Off => 4,
+        }
+    }
+}
+
+impl TryFrom<u8> for Mode {
+    type Error = StateError;
+
+    fn try_from(val: u8) -> Result<Mode, StateError> {
+        match val {
+            0 => Ok(Mode::Mode0),
+            1 => Ok(Mode::Mode1),
+            2 => Ok(Mode::Mode2),
+            3 => Ok(Mode::Mode3),
+            4 => Ok(Mode::Off),
+            _ => Err(StateError::InvalidValue("ppu mode")),
+        }
+    }
+}
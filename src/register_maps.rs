@@ -1,3 +1,7 @@
+use crate::apu::APU;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+use crate::sgb::Sgb;
+
 #[derive(Copy, Clone, Default)]
 pub struct InterruptEnable {
     pub vblank: u8,
@@ -38,6 +42,29 @@ pub struct InterruptFlag {
     pub joypad: u8,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+    VBlank,
+    LcdStat,
+    Timer,
+    Serial,
+    Joypad,
+}
+
+// IF is the interrupt controller every component raises its requests through, the cpu
+// services them by priority against IE
+impl InterruptFlag {
+    pub fn request(&mut self, interrupt: Interrupt) {
+        match interrupt {
+            Interrupt::VBlank => self.vblank = 1,
+            Interrupt::LcdStat => self.lcd_stat = 1,
+            Interrupt::Timer => self.timer = 1,
+            Interrupt::Serial => self.serial = 1,
+            Interrupt::Joypad => self.joypad = 1,
+        }
+    }
+}
+
 impl From<InterruptFlag> for u8 {
     fn from(item: InterruptFlag) -> Self {
         (item.joypad << 4)
@@ -209,7 +236,161 @@ impl From<TAC> for u8 {
     }
 }
 
-#[derive(Copy, Clone, Default)]
+#[derive(Copy, Clone, Default, Debug)]
+pub struct Key1 {
+    pub prepare_switch: bool, // bit 0, the next STOP switches speed
+    pub double_speed: bool,   // bit 7
+}
+
+// Cgb color palette memory behind an index register and a data register. Eight
+// palettes of four little endian RGB555 colors
+#[derive(Copy, Clone, Debug)]
+pub struct PaletteRam {
+    pub index: u8,
+    pub auto_increment: bool,
+    pub data: [u8; 64],
+}
+
+impl Default for PaletteRam {
+    fn default() -> Self {
+        PaletteRam {
+            index: 0,
+            auto_increment: false,
+            data: [0xFF; 64], // the cgb boot rom leaves background palettes white
+        }
+    }
+}
+
+impl PaletteRam {
+    pub fn spec(&self) -> u8 {
+        ((self.auto_increment as u8) << 7) | 0x40 | self.index
+    }
+
+    pub fn set_spec(&mut self, value: u8) {
+        self.auto_increment = value & 0x80 != 0;
+        self.index = value & 0x3F;
+    }
+
+    pub fn read_data(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+
+    // Only writes advance the index
+    pub fn write_data(&mut self, value: u8) {
+        self.data[self.index as usize] = value;
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3F;
+        }
+    }
+
+    pub fn color(&self, palette: usize, color: usize) -> u16 {
+        let offset = palette * 8 + color * 2;
+        self.data[offset] as u16 | ((self.data[offset + 1] as u16) << 8)
+    }
+}
+
+impl SaveState for PaletteRam {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.index);
+        writer.bool(self.auto_increment);
+        writer.bytes(&self.data);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.index = reader.u8()? & 0x3F;
+        self.auto_increment = reader.bool()?;
+        reader.bytes(&mut self.data)
+    }
+}
+
+// Cgb vram dma. General purpose transfers copy everything at once while the cpu waits,
+// h-blank transfers copy one 16 byte block at the start of every h-blank
+#[derive(Copy, Clone, Debug)]
+pub struct Hdma {
+    pub source: u16,
+    pub destination: u16, // offset into the vram bank
+    pub blocks_left: u8,  // minus one, wraps to 0x7F when done
+    pub hblank_active: bool,
+    pub stall_cycles: u16, // cpu steps left waiting for the last transfer
+}
+
+impl Default for Hdma {
+    fn default() -> Self {
+        Hdma {
+            source: 0,
+            destination: 0,
+            blocks_left: 0x7F,
+            hblank_active: false,
+            stall_cycles: 0,
+        }
+    }
+}
+
+impl Hdma {
+    // Only the length register can be read back, bit 7 is clear while a h-blank
+    // transfer is running
+    pub fn get(&self, index: usize) -> u8 {
+        match index {
+            0xFF55 => ((!self.hblank_active as u8) << 7) | self.blocks_left,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn set_address(&mut self, index: usize, value: u8) {
+        match index {
+            0xFF51 => self.source = (self.source & 0x00FF) | ((value as u16) << 8),
+            0xFF52 => self.source = (self.source & 0xFF00) | (value & 0xF0) as u16,
+            0xFF53 => self.destination = (self.destination & 0x00FF) | (((value & 0x1F) as u16) << 8),
+            0xFF54 => self.destination = (self.destination & 0x1F00) | (value & 0xF0) as u16,
+            _ => panic!("invalid index for hdma: {:#x}", index),
+        }
+    }
+
+    // Writing with bit 7 clear during a h-blank transfer cancels it, otherwise bit 7 picks
+    // h-blank over general purpose. True when a general purpose transfer should run
+    pub fn write_control(&mut self, value: u8) -> bool {
+        if self.hblank_active && value & 0x80 == 0 {
+            self.hblank_active = false;
+            return false;
+        }
+        self.blocks_left = value & 0x7F;
+        self.hblank_active = value & 0x80 != 0;
+        !self.hblank_active
+    }
+
+    // Advances past a copied block, true once the last one is done
+    pub fn block_done(&mut self) -> bool {
+        self.source = self.source.wrapping_add(0x10);
+        self.destination = (self.destination + 0x10) & 0x1FF0;
+        self.blocks_left = self.blocks_left.wrapping_sub(1) & 0x7F;
+        let done = self.blocks_left == 0x7F;
+        if done {
+            self.hblank_active = false;
+        }
+        done
+    }
+}
+
+impl SaveState for Hdma {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.u16(self.source);
+        writer.u16(self.destination);
+        writer.u8(self.blocks_left);
+        writer.bool(self.hblank_active);
+        writer.u16(self.stall_cycles);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.source = reader.u16()? & 0xFFF0;
+        self.destination = reader.u16()? & 0x1FF0;
+        self.blocks_left = reader.u8()? & 0x7F;
+        self.hblank_active = reader.bool()?;
+        self.stall_cycles = reader.u16()?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Default)]
 pub struct IORegisters {
     pub dma_in_progress: bool,
     pub clock_count_for_dma: u8,
@@ -217,8 +398,7 @@ pub struct IORegisters {
     pub communication: [u8; 2],        //0xFF01 - 0xFF02
     pub timer: Timer,                  //0xFF04 - 0xFF07
     pub interrupt_flag: InterruptFlag, // 0xFF0F
-    pub sound: [u8; 0x17],             // 0xFF10 - 0xFF26
-    pub waveform_ram: [u8; 0x10],      // 0xFF30 - 0xFF3F
+    pub apu: APU,                      // 0xFF10 - 0xFF26, 0xFF30 - 0xFF3F
     pub lcdc: LCDC,                    // 0xFF40
     pub lcd_status: LCDStatus,         // 0xFF41
     pub scy: u8,                       // 0xFF42
@@ -231,8 +411,17 @@ pub struct IORegisters {
     pub obp1: u8,                      // 0xFF49
     pub wy: u8,                        // 0xFF4A
     pub wx: u8,                        // 0xFF4B
-    pub vbk: u8,                       // 0xFF4F, not used
+    pub key1: Key1,                    // 0xFF4D, cgb only
+    pub vbk: u8,                       // 0xFF4F, cgb only
     pub use_boot_rom: u8,              //0xFF50
+    pub hdma: Hdma,                    // 0xFF51 - 0xFF55, cgb only
+    pub bg_palettes: PaletteRam,       // 0xFF68 - 0xFF69, cgb only
+    pub obj_palettes: PaletteRam,      // 0xFF6A - 0xFF6B, cgb only
+    pub svbk: u8,                      // 0xFF70, cgb only
+    pub sgb: Sgb,                      // packets sent through 0xFF00
+    pub cgb_mode: bool,
+    apu_phase: bool, // the apu keeps normal speed in double speed mode
+    stat_line: bool, // all enabled stat sources ored together
     pub other1: [u8; 0x20],            // 0xFF51 - 0xFF70
     pub other2: [u8; 0x10],            //0xFF70-0xFF7F
 }
@@ -240,15 +429,17 @@ pub struct IORegisters {
 impl IORegisters {
     pub fn get(&self, index: usize) -> u8 {
         match index {
-            0xFF00 => self.joypad.into(),
+            0xFF00 => match self.sgb.joypad_id() {
+                Some(id) => (u8::from(self.joypad) & 0xF0) | id,
+                None => self.joypad.into(),
+            },
             0xFF01 | 0xFF02 => self.communication[index - 0xFF01],
             0xFF04 => (self.timer.timer_counter >> 8) as u8,
             0xFF05 => self.timer.tima,
             0xFF06 => self.timer.tma,
             0xFF07 => self.timer.tac.into(),
             0xFF0F => self.interrupt_flag.into(),
-            0xFF10..=0xFF26 => self.sound[index - 0xFF10],
-            0xFF30..=0xFF3F => self.waveform_ram[index - 0xFF30],
+            0xFF10..=0xFF3F => self.apu.get(index),
             0xFF40 => self.lcdc.into(),
             0xFF41 => self.lcd_status.into(),
             0xFF42 => self.scy,
@@ -261,10 +452,17 @@ impl IORegisters {
             0xFF49 => self.obp1,
             0xFF4A => self.wy,
             0xFF4B => self.wx,
-            0xFF4D => 0xFF,
-            0xFF4F => self.vbk,
+            0xFF4D | 0xFF4F | 0xFF51..=0xFF55 | 0xFF68..=0xFF6B | 0xFF70 if !self.cgb_mode => 0xFF,
+            0xFF4D => 0x7E | ((self.key1.double_speed as u8) << 7) | self.key1.prepare_switch as u8,
+            0xFF4F => 0xFE | self.vbk,
             0xFF50 => self.use_boot_rom,
-            0xFF51..=0xFF70 => self.other1[index - 0xFF51],
+            0xFF51..=0xFF55 => self.hdma.get(index),
+            0xFF68 => self.bg_palettes.spec(),
+            0xFF69 => self.bg_palettes.read_data(),
+            0xFF6A => self.obj_palettes.spec(),
+            0xFF6B => self.obj_palettes.read_data(),
+            0xFF70 => 0xF8 | self.svbk,
+            0xFF56..=0xFF70 => self.other1[index - 0xFF51],
             0xFF71..=0xFF7F => self.other2[index - 0xFF71],
             _ => panic!("invalid index for io registers: {:#x}", index),
         }
@@ -272,17 +470,44 @@ impl IORegisters {
 
     pub fn set(&mut self, index: usize, value: u8) {
         match index {
-            0xFF00 => self.joypad = value.into(),
+            0xFF00 => {
+                if self.sgb.enabled {
+                    self.sgb.write_joypad(value);
+                }
+                self.joypad = value.into();
+            }
             0xFF01 | 0xFF02 => self.communication[index - 0xFF01] = value,
-            0xFF04 => self.timer.timer_counter = 0,
+            0xFF04 => {
+                // resetting div can also produce the falling edge the apu listens for
+                if self.timer.div_apu_bit(self.key1.double_speed) {
+                    self.apu.step_frame_sequencer();
+                }
+                self.timer.timer_counter = 0;
+            }
             0xFF05 => self.timer.tima = value,
             0xFF06 => self.timer.tma = value,
             0xFF07 => self.timer.tac = value.into(),
             0xFF0F => self.interrupt_flag = value.into(),
-            0xFF10..=0xFF26 => self.sound[index - 0xFF10] = value,
-            0xFF30..=0xFF3F => self.waveform_ram[index - 0xFF30] = value,
+            0xFF10..=0xFF3F => self.apu.set(index, value),
             0xFF40 => self.lcdc = value.into(),
-            0xFF41 => self.lcd_status = value.into(),
+            0xFF41 => {
+                // the dmg sees every source enabled for the cycle of the write
+                if !self.cgb_mode
+                    && self.lcdc.lcd_ppu_enable == 1
+                    && (self.lcd_status.mode != 3 || self.lcd_status.lyc_eq_ly == 1)
+                {
+                    if !self.stat_line {
+                        self.interrupt_flag.request(Interrupt::LcdStat);
+                    }
+                    self.stat_line = true;
+                }
+                // mode and the LY=LYC flag are read only
+                let mut lcd_status = LCDStatus::from(value);
+                lcd_status.mode = self.lcd_status.mode;
+                lcd_status.lyc_eq_ly = self.lcd_status.lyc_eq_ly;
+                self.lcd_status = lcd_status;
+                self.update_stat_line();
+            }
             0xFF42 => self.scy = value,
             0xFF43 => self.scx = value,
             0xFF44 => self.ly = value,
@@ -299,22 +524,161 @@ impl IORegisters {
             0xFF49 => self.obp1 = value,
             0xFF4A => self.wy = value,
             0xFF4B => self.wx = value,
-            0xFF4F => self.vbk = value,
+            0xFF4D | 0xFF4F | 0xFF51..=0xFF55 | 0xFF68..=0xFF6B | 0xFF70 if !self.cgb_mode => (),
+            0xFF4D => self.key1.prepare_switch = value & 1 != 0,
+            0xFF4F => self.vbk = value & 1,
             0xFF50 => {
                 self.use_boot_rom = value;
                 self.joypad = 0xFF.into(); // 0 means pressed which resets Tetris
             }
-            0xFF51..=0xFF70 => self.other1[index - 0xFF51] = value,
+            0xFF51..=0xFF54 => self.hdma.set_address(index, value),
+            0xFF55 => {
+                // general purpose transfers need the memory map, CPU::write_data runs them
+                self.hdma.write_control(value);
+            }
+            0xFF68 => self.bg_palettes.set_spec(value),
+            0xFF69 => self.bg_palettes.write_data(value),
+            0xFF6A => self.obj_palettes.set_spec(value),
+            0xFF6B => self.obj_palettes.write_data(value),
+            0xFF70 => self.svbk = value & 0x07,
+            0xFF56..=0xFF70 => self.other1[index - 0xFF51] = value,
             0xFF71..=0xFF7F => self.other2[index - 0xFF71] = value,
             _ => panic!("invalid index for io registers: 0x{:x}", index),
         }
     }
 
     pub fn tick_timer(&mut self) {
+        let double_speed = self.key1.double_speed;
+        let div_apu_bit = self.timer.div_apu_bit(double_speed);
         if self.timer.tick_timer() {
-            self.interrupt_flag.timer = 1;
+            self.interrupt_flag.request(Interrupt::Timer);
+        }
+        if div_apu_bit && !self.timer.div_apu_bit(double_speed) {
+            self.apu.step_frame_sequencer();
+        }
+        self.apu_phase = !self.apu_phase;
+        if !double_speed || self.apu_phase {
+            self.apu.tick();
         }
     }
+
+    // Stat interrupts fire when the OR of all enabled sources goes from low to high, so
+    // while one source holds the line up the others cannot raise another interrupt
+    pub fn update_stat_line(&mut self) {
+        let lcd_status = self.lcd_status;
+        let mode_source = match lcd_status.mode {
+            0 => lcd_status.mode_zero_stat_interrupt,
+            1 => lcd_status.mode_one_stat_interrupt,
+            2 => lcd_status.mode_two_stat_interrupt,
+            _ => 0,
+        };
+        let lyc_source = lcd_status.lyc_ly_stat_interrupt & lcd_status.lyc_eq_ly;
+        let stat_line = self.lcdc.lcd_ppu_enable == 1 && (mode_source | lyc_source) == 1;
+        if stat_line && !self.stat_line {
+            self.interrupt_flag.request(Interrupt::LcdStat);
+        }
+        self.stat_line = stat_line;
+    }
+
+    // Bank mapped at 0xD000 - 0xDFFF, bank 0 selects 1
+    pub fn wram_bank(&self) -> usize {
+        if self.cgb_mode {
+            (self.svbk as usize).max(1)
+        } else {
+            1
+        }
+    }
+
+    // Run by STOP, true when the speed was switched
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.cgb_mode || !self.key1.prepare_switch {
+            return false;
+        }
+        self.key1.double_speed = !self.key1.double_speed;
+        self.key1.prepare_switch = false;
+        self.timer.set_div_counter(0);
+        true
+    }
+}
+
+impl SaveState for IORegisters {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.dma_in_progress);
+        writer.u8(self.clock_count_for_dma);
+        writer.u8(self.joypad.into());
+        writer.bytes(&self.communication);
+        self.timer.save_state(writer);
+        writer.u8(self.interrupt_flag.into());
+        self.apu.save_state(writer);
+        writer.u8(self.lcdc.into());
+        writer.u8(self.lcd_status.into());
+        writer.bytes(&[
+            self.scy,
+            self.scx,
+            self.ly,
+            self.lyc,
+            self.dma,
+            self.bgp,
+            self.obp0,
+            self.obp1,
+            self.wy,
+            self.wx,
+            self.vbk,
+            self.use_boot_rom,
+        ]);
+        writer.bytes(&self.other1);
+        writer.bytes(&self.other2);
+        writer.bool(self.key1.prepare_switch);
+        writer.bool(self.key1.double_speed);
+        self.bg_palettes.save_state(writer);
+        self.obj_palettes.save_state(writer);
+        writer.u8(self.svbk);
+        writer.bool(self.cgb_mode);
+        writer.bool(self.apu_phase);
+        writer.bool(self.stat_line);
+        self.hdma.save_state(writer);
+        self.sgb.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.dma_in_progress = reader.bool()?;
+        self.clock_count_for_dma = reader.u8()?;
+        self.joypad = reader.u8()?.into();
+        reader.bytes(&mut self.communication)?;
+        self.timer.load_state(reader)?;
+        self.interrupt_flag = reader.u8()?.into();
+        self.apu.load_state(reader)?;
+        self.lcdc = reader.u8()?.into();
+        self.lcd_status = reader.u8()?.into();
+        let mut regs = [0; 12];
+        reader.bytes(&mut regs)?;
+        let [scy, scx, ly, lyc, dma, bgp, obp0, obp1, wy, wx, vbk, use_boot_rom] = regs;
+        self.scy = scy;
+        self.scx = scx;
+        self.ly = ly;
+        self.lyc = lyc;
+        self.dma = dma;
+        self.bgp = bgp;
+        self.obp0 = obp0;
+        self.obp1 = obp1;
+        self.wy = wy;
+        self.wx = wx;
+        self.vbk = vbk;
+        self.use_boot_rom = use_boot_rom;
+        reader.bytes(&mut self.other1)?;
+        reader.bytes(&mut self.other2)?;
+        self.key1.prepare_switch = reader.bool()?;
+        self.key1.double_speed = reader.bool()?;
+        self.bg_palettes.load_state(reader)?;
+        self.obj_palettes.load_state(reader)?;
+        self.svbk = reader.u8()? & 0x07;
+        self.cgb_mode = reader.bool()?;
+        self.apu_phase = reader.bool()?;
+        self.stat_line = reader.bool()?;
+        self.hdma.load_state(reader)?;
+        self.sgb.load_state(reader)?;
+        Ok(())
+    }
 }
 
 #[derive(Copy, Clone, Default, Debug)]
@@ -325,7 +689,41 @@ pub struct Timer {
     timer_counter: u16, // High bits are div -> 0xFF04
 }
 
+impl SaveState for Timer {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.tima);
+        writer.u8(self.tma);
+        writer.bool(self.tac.timer_enable);
+        writer.u8(self.tac.input_clock_select as u8);
+        writer.u16(self.timer_counter);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.tima = reader.u8()?;
+        self.tma = reader.u8()?;
+        let timer_enable = reader.bool()?;
+        let clock_select: TAC = reader.u8()?.into();
+        self.tac = TAC {
+            timer_enable,
+            input_clock_select: clock_select.input_clock_select,
+        };
+        self.timer_counter = reader.u16()?;
+        Ok(())
+    }
+}
+
 impl Timer {
+    pub fn set_div_counter(&mut self, counter: u16) {
+        self.timer_counter = counter;
+    }
+
+    // Bit 4 of div (bit 5 in double speed), its falling edge clocks the apu frame
+    // sequencer at 512Hz
+    pub fn div_apu_bit(&self, double_speed: bool) -> bool {
+        let bit = if double_speed { 0x2000 } else { 0x1000 };
+        self.timer_counter & bit != 0
+    }
+
     pub fn tick_timer(&mut self) -> bool {
         let old_timer = self.timer_counter;
         self.timer_counter = self.timer_counter.wrapping_add(4);
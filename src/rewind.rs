@@ -0,0 +1,182 @@
+use std::collections::VecDeque;
+
+// Keeps recent save states so play can be stepped backwards. Only the newest state is
+// stored whole, every older one is kept as the run length encoded xor against its newer
+// neighbour, which is mostly zeros since little changes between snapshots
+pub struct RewindBuffer {
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+    memory_used: usize,
+    pub memory_cap: usize,
+    pub interval: u32, // frames between snapshots
+    frame_counter: u32,
+}
+
+impl RewindBuffer {
+    pub fn new(interval: u32, memory_cap: usize) -> RewindBuffer {
+        RewindBuffer {
+            latest: None,
+            deltas: VecDeque::new(),
+            memory_used: 0,
+            memory_cap,
+            interval,
+            frame_counter: 0,
+        }
+    }
+
+    // Called once per frame, true when a snapshot should be pushed
+    pub fn frame_tick(&mut self) -> bool {
+        self.frame_counter += 1;
+        if self.frame_counter >= self.interval {
+            self.frame_counter = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(latest) = self.latest.take() {
+            if latest.len() == state.len() {
+                let delta = compress(&xor(&latest, &state));
+                self.memory_used = self.memory_used - latest.len() + delta.len();
+                self.deltas.push_back(delta);
+            } else {
+                self.clear();
+            }
+        }
+        self.memory_used += state.len();
+        self.latest = Some(state);
+        while self.memory_used > self.memory_cap {
+            match self.deltas.pop_front() {
+                Some(oldest) => self.memory_used -= oldest.len(),
+                None => break,
+            }
+        }
+    }
+
+    // Newest snapshot first; the last one stays so rewinding stops instead of emptying out
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let latest = self.latest.take()?;
+        match self.deltas.pop_back() {
+            Some(delta) => {
+                self.memory_used -= delta.len();
+                let previous = xor(&latest, &decompress(&delta));
+                self.latest = Some(previous);
+                Some(latest)
+            }
+            None => {
+                self.latest = Some(latest.clone());
+                Some(latest)
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.latest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+        self.memory_used = 0;
+        self.frame_counter = 0;
+    }
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b.iter()).map(|(x, y)| x ^ y).collect()
+}
+
+// PackBits style: a control byte below 0x80 is followed by that many + 1 literal bytes,
+// otherwise the next byte repeats (control - 0x80) + 2 times
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let mut run = 1;
+        while i + run < data.len() && data[i + run] == data[i] && run < 0x81 {
+            run += 1;
+        }
+        if run >= 2 {
+            out.push(0x80 + (run - 2) as u8);
+            out.push(data[i]);
+            i += run;
+        } else {
+            let start = i;
+            while i < data.len()
+                && i - start < 0x80
+                && !(i + 1 < data.len() && data[i + 1] == data[i])
+            {
+                i += 1;
+            }
+            if i == start {
+                i += 1;
+            }
+            out.push((i - start - 1) as u8);
+            out.extend_from_slice(&data[start..i]);
+        }
+    }
+    out
+}
+
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i] as usize;
+        if control < 0x80 {
+            out.extend_from_slice(&data[i + 1..i + 2 + control]);
+            i += control + 2;
+        } else {
+            out.resize(out.len() + control - 0x80 + 2, data[i + 1]);
+            i += 2;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_round_trip() {
+        let mut data = vec![0; 1000];
+        data[10] = 1;
+        data[11] = 2;
+        data[500..700].iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+        let compressed = compress(&data);
+        assert!(compressed.len() < data.len() / 2);
+        assert_eq!(decompress(&compressed), data);
+        assert_eq!(decompress(&compress(&[7])), vec![7]);
+    }
+
+    #[test]
+    fn test_pop_order_and_cap() {
+        let mut rewind = RewindBuffer::new(1, 3000);
+        for i in 0..10u8 {
+            let mut state = vec![0; 1000];
+            state[0] = i;
+            rewind.push(state);
+        }
+        // the newest state plus as many small deltas as fit under the cap
+        assert_eq!(rewind.len(), 10);
+        assert_eq!(rewind.pop().unwrap()[0], 9);
+        assert_eq!(rewind.pop().unwrap()[0], 8);
+
+        let mut rewind = RewindBuffer::new(1, 1050);
+        for i in 0..10u8 {
+            rewind.push(vec![i; 1000]);
+        }
+        assert!(rewind.len() < 10);
+        while rewind.len() > 1 {
+            rewind.pop();
+        }
+        assert_eq!(rewind.pop().unwrap()[0], rewind.pop().unwrap()[0]);
+    }
+}
@@ -0,0 +1,140 @@
+use byteorder::{ByteOrder, LittleEndian};
+use std::fmt;
+
+pub const STATE_MAGIC: &[u8; 4] = b"RBST";
+pub const STATE_VERSION: u32 = 9;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    BadMagic,
+    UnsupportedVersion(u32),
+    UnexpectedEof,
+    RomMismatch,
+    InvalidValue(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "not a rustboy save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version: {}", version)
+            }
+            StateError::UnexpectedEof => write!(f, "save state is truncated"),
+            StateError::RomMismatch => write!(f, "save state belongs to a different rom"),
+            StateError::InvalidValue(what) => write!(f, "save state has an invalid {}", what),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+// Implemented by every component next to its fields, so private state stays private
+pub trait SaveState {
+    fn save_state(&self, writer: &mut StateWriter);
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError>;
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { buf: Vec::new() }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+
+    pub fn bool(&mut self, val: bool) {
+        self.buf.push(val as u8);
+    }
+
+    pub fn u16(&mut self, val: u16) {
+        let mut bytes = [0; 2];
+        LittleEndian::write_u16(&mut bytes, val);
+        self.buf.extend_from_slice(&bytes);
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        let mut bytes = [0; 4];
+        LittleEndian::write_u32(&mut bytes, val);
+        self.buf.extend_from_slice(&bytes);
+    }
+
+    pub fn u64(&mut self, val: u64) {
+        let mut bytes = [0; 8];
+        LittleEndian::write_u64(&mut bytes, val);
+        self.buf.extend_from_slice(&bytes);
+    }
+
+    pub fn bytes(&mut self, val: &[u8]) {
+        self.buf.extend_from_slice(val);
+    }
+
+    // Length prefixed, for buffers whose size depends on the cartridge
+    pub fn sized_bytes(&mut self, val: &[u8]) {
+        self.u32(val.len() as u32);
+        self.bytes(val);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() - self.pos < len {
+            return Err(StateError::UnexpectedEof);
+        }
+        let slice = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(LittleEndian::read_u16(self.take(2)?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(LittleEndian::read_u32(self.take(4)?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(LittleEndian::read_u64(self.take(8)?))
+    }
+
+    pub fn bytes(&mut self, out: &mut [u8]) -> Result<(), StateError> {
+        out.copy_from_slice(self.take(out.len())?);
+        Ok(())
+    }
+
+    // The stored length has to match the buffer being restored
+    pub fn sized_bytes(&mut self, out: &mut [u8]) -> Result<(), StateError> {
+        if self.u32()? as usize != out.len() {
+            return Err(StateError::InvalidValue("buffer length"));
+        }
+        self.bytes(out)
+    }
+}
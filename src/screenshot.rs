@@ -0,0 +1,60 @@
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::io::{self, Write};
+
+const FILE_HEADER_SIZE: u32 = 14;
+const INFO_HEADER_SIZE: u32 = 40;
+
+// Uncompressed 24 bit BMP from ARGB8888 pixels, rows are stored bottom up and padded
+// to a multiple of 4 bytes
+pub fn write_bmp<W: Write>(out: &mut W, width: usize, height: usize, pixels: &[u32]) -> io::Result<()> {
+    assert_eq!(pixels.len(), width * height, "pixel count does not match the size");
+    let row_size = (width * 3 + 3) & !3;
+    let image_size = (row_size * height) as u32;
+    let data_offset = FILE_HEADER_SIZE + INFO_HEADER_SIZE;
+
+    out.write_all(b"BM")?;
+    out.write_u32::<LittleEndian>(data_offset + image_size)?;
+    out.write_u32::<LittleEndian>(0)?;
+    out.write_u32::<LittleEndian>(data_offset)?;
+
+    out.write_u32::<LittleEndian>(INFO_HEADER_SIZE)?;
+    out.write_i32::<LittleEndian>(width as i32)?;
+    out.write_i32::<LittleEndian>(height as i32)?;
+    out.write_u16::<LittleEndian>(1)?; // planes
+    out.write_u16::<LittleEndian>(24)?;
+    out.write_u32::<LittleEndian>(0)?; // no compression
+    out.write_u32::<LittleEndian>(image_size)?;
+    out.write_i32::<LittleEndian>(2835)?; // 72 dpi
+    out.write_i32::<LittleEndian>(2835)?;
+    out.write_u32::<LittleEndian>(0)?;
+    out.write_u32::<LittleEndian>(0)?;
+
+    let mut row = Vec::with_capacity(row_size);
+    for line in pixels.chunks_exact(width).rev() {
+        row.clear();
+        for pixel in line {
+            row.extend_from_slice(&[*pixel as u8, (*pixel >> 8) as u8, (*pixel >> 16) as u8]);
+        }
+        row.resize(row_size, 0);
+        out.write_all(&row)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bmp_layout() {
+        let mut out = Vec::new();
+        write_bmp(&mut out, 2, 2, &[0xFF112233, 0xFF445566, 0xFF778899, 0xFFAABBCC]).unwrap();
+        // two rows of 6 bytes padded to 8
+        assert_eq!(out.len(), 54 + 16);
+        assert_eq!(&out[0..2], b"BM");
+        assert_eq!(out[2], 70);
+        // bottom row first, in blue green red order
+        assert_eq!(&out[54..62], &[0x99, 0x88, 0x77, 0xCC, 0xBB, 0xAA, 0, 0]);
+        assert_eq!(&out[62..70], &[0x33, 0x22, 0x11, 0x66, 0x55, 0x44, 0, 0]);
+    }
+}
@@ -0,0 +1,373 @@
+use crate::ppu::{rgb555_to_argb, GameboyColor};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+const PACKET_SIZE: usize = 16;
+const ATTR_WIDTH: usize = 20; // the attribute map has one entry per 8x8 cell
+const ATTR_HEIGHT: usize = 18;
+
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const ATTR_LIN: u8 = 0x05;
+const MLT_REQ: u8 = 0x11;
+const MASK_EN: u8 = 0x17;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Mask {
+    #[default]
+    Off,
+    Freeze, // keep showing the last frame
+    Black,
+    Color0,
+}
+
+impl From<u8> for Mask {
+    fn from(value: u8) -> Mask {
+        match value & 0x03 {
+            0 => Mask::Off,
+            1 => Mask::Freeze,
+            2 => Mask::Black,
+            _ => Mask::Color0,
+        }
+    }
+}
+
+impl From<Mask> for u8 {
+    fn from(mask: Mask) -> u8 {
+        match mask {
+            Mask::Off => 0,
+            Mask::Freeze => 1,
+            Mask::Black => 2,
+            Mask::Color0 => 3,
+        }
+    }
+}
+
+// Super Game Boy commands arrive as 16 byte packets bit banged through P14 and P15 of
+// the joypad register. Both lines low starts a packet, P14 low sends a 0 and P15 low a 1,
+// both high separates the pulses. The first byte holds the command and its packet count
+#[derive(Clone)]
+pub struct Sgb {
+    pub enabled: bool,
+    lines: u8, // P14 and P15 from the last write
+    receiving: bool,
+    bit_index: usize,
+    packet: [u8; PACKET_SIZE],
+    command: Vec<u8>, // packets of a command so far
+    pub palettes: [[u16; 4]; 4],
+    pub attributes: [u8; ATTR_WIDTH * ATTR_HEIGHT],
+    pub mask: Mask,
+    players: u8,
+    player: u8,
+}
+
+impl Default for Sgb {
+    fn default() -> Self {
+        Sgb {
+            enabled: false,
+            lines: 0x30,
+            receiving: false,
+            bit_index: 0,
+            packet: [0; PACKET_SIZE],
+            command: Vec::new(),
+            palettes: [[0x7FFF, 0x56B5, 0x294A, 0x0000]; 4],
+            attributes: [0; ATTR_WIDTH * ATTR_HEIGHT],
+            mask: Mask::Off,
+            players: 1,
+            player: 0,
+        }
+    }
+}
+
+impl Sgb {
+    pub fn write_joypad(&mut self, value: u8) {
+        let lines = value & 0x30;
+        match lines {
+            // the reset pulse pulls both lines low from idle
+            0x00 if self.lines == 0x30 => {
+                self.receiving = true;
+                self.bit_index = 0;
+                self.packet = [0; PACKET_SIZE];
+            }
+            0x10 | 0x20 if self.receiving && self.lines == 0x30 => {
+                if self.bit_index == PACKET_SIZE * 8 {
+                    // stop bit
+                    self.receiving = false;
+                    self.packet_received();
+                } else {
+                    let bit = (lines == 0x10) as u8;
+                    self.packet[self.bit_index / 8] |= bit << (self.bit_index % 8);
+                    self.bit_index += 1;
+                }
+            }
+            _ => (),
+        }
+        // with multiplayer on, releasing P15 moves on to the next controller
+        if self.players > 1 && lines & 0x20 != 0 && self.lines & 0x20 == 0 {
+            self.player = (self.player + 1) % self.players;
+        }
+        self.lines = lines;
+    }
+
+    // Low nibble of the joypad register while neither button group is selected, 0xF is
+    // the first controller, 0xE the second and so on
+    pub fn joypad_id(&self) -> Option<u8> {
+        if self.enabled && self.players > 1 && self.lines == 0x30 {
+            Some(0x0F - self.player)
+        } else {
+            None
+        }
+    }
+
+    fn packet_received(&mut self) {
+        self.command.extend_from_slice(&self.packet);
+        let packets = (self.command[0] & 0x07).max(1) as usize;
+        if self.command.len() >= packets * PACKET_SIZE {
+            let command = std::mem::take(&mut self.command);
+            self.run_command(&command);
+        }
+    }
+
+    fn run_command(&mut self, data: &[u8]) {
+        match data[0] >> 3 {
+            PAL01 => self.set_palettes(0, 1, data),
+            PAL23 => self.set_palettes(2, 3, data),
+            PAL03 => self.set_palettes(0, 3, data),
+            PAL12 => self.set_palettes(1, 2, data),
+            ATTR_BLK => {
+                let count = (data[1] as usize).min((data.len() - 2) / 6);
+                for block in data[2..2 + count * 6].chunks_exact(6) {
+                    self.attr_block(block);
+                }
+            }
+            ATTR_LIN => {
+                let count = (data[1] as usize).min(data.len() - 2);
+                for line in &data[2..2 + count] {
+                    self.attr_line(*line);
+                }
+            }
+            MLT_REQ => {
+                self.players = match data[1] & 0x03 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+                self.player = 0;
+            }
+            MASK_EN => self.mask = data[1].into(),
+            _ => (),
+        }
+    }
+
+    // Color 0 is shared by all palettes, followed by colors 1 - 3 of both palettes
+    fn set_palettes(&mut self, first: usize, second: usize, data: &[u8]) {
+        let color = |i: usize| (data[1 + i * 2] as u16 | (data[2 + i * 2] as u16) << 8) & 0x7FFF;
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color(0);
+        }
+        for i in 1..4 {
+            self.palettes[first][i] = color(i);
+            self.palettes[second][i] = color(i + 3);
+        }
+    }
+
+    // Control bits pick which of inside, border and outside change, the palette byte
+    // holds their palettes in that order. Setting only inside or only outside also
+    // colors the border with the same palette
+    fn attr_block(&mut self, block: &[u8]) {
+        let control = block[0] & 0x07;
+        let inside = block[1] & 0x03;
+        let outside = (block[1] >> 4) & 0x03;
+        let border = match control {
+            0x01 => Some(inside),
+            0x04 => Some(outside),
+            _ if control & 0x02 != 0 => Some((block[1] >> 2) & 0x03),
+            _ => None,
+        };
+        let (x1, y1) = (block[2] as usize & 0x1F, block[3] as usize & 0x1F);
+        let (x2, y2) = (block[4] as usize & 0x1F, block[5] as usize & 0x1F);
+        for y in 0..ATTR_HEIGHT {
+            for x in 0..ATTR_WIDTH {
+                let within = (x1..=x2).contains(&x) && (y1..=y2).contains(&y);
+                let palette = if within && (x == x1 || x == x2 || y == y1 || y == y2) {
+                    border
+                } else if within {
+                    (control & 0x01 != 0).then_some(inside)
+                } else {
+                    (control & 0x04 != 0).then_some(outside)
+                };
+                if let Some(palette) = palette {
+                    self.attributes[y * ATTR_WIDTH + x] = palette;
+                }
+            }
+        }
+    }
+
+    // Bits 0 - 4 are the row or column, 5 - 6 the palette and bit 7 picks a row
+    fn attr_line(&mut self, line: u8) {
+        let index = (line & 0x1F) as usize;
+        let palette = (line >> 5) & 0x03;
+        if line & 0x80 != 0 {
+            if index < ATTR_HEIGHT {
+                self.attributes[index * ATTR_WIDTH..(index + 1) * ATTR_WIDTH].fill(palette);
+            }
+        } else if index < ATTR_WIDTH {
+            for y in 0..ATTR_HEIGHT {
+                self.attributes[y * ATTR_WIDTH + index] = palette;
+            }
+        }
+    }
+
+    // ARGB8888 for a shade on the screen, colored by the attribute cell it falls in.
+    // Freezing is up to the caller, it has the previous frame
+    pub fn color(&self, x: usize, y: usize, shade: GameboyColor) -> u32 {
+        let shade = match shade {
+            GameboyColor::White => 0,
+            GameboyColor::LightGray => 1,
+            GameboyColor::DarkGray => 2,
+            GameboyColor::Black | GameboyColor::Transparent => 3,
+        };
+        match self.mask {
+            Mask::Black => 0xFF000000,
+            Mask::Color0 => rgb555_to_argb(self.palettes[0][0]),
+            Mask::Off | Mask::Freeze => {
+                let palette = self.attributes[(y / 8) * ATTR_WIDTH + x / 8] as usize;
+                rgb555_to_argb(self.palettes[palette][shade])
+            }
+        }
+    }
+}
+
+impl SaveState for Sgb {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.enabled);
+        writer.u8(self.lines);
+        writer.bool(self.receiving);
+        writer.u8(self.bit_index as u8);
+        writer.bytes(&self.packet);
+        writer.u8(self.command.len() as u8);
+        writer.bytes(&self.command);
+        for color in self.palettes.iter().flatten() {
+            writer.u16(*color);
+        }
+        writer.bytes(&self.attributes);
+        writer.u8(self.mask.into());
+        writer.u8(self.players);
+        writer.u8(self.player);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.bool()?;
+        self.lines = reader.u8()? & 0x30;
+        self.receiving = reader.bool()?;
+        self.bit_index = (reader.u8()? as usize).min(PACKET_SIZE * 8);
+        reader.bytes(&mut self.packet)?;
+        let command_len = reader.u8()? as usize;
+        if command_len > PACKET_SIZE * 7 {
+            return Err(StateError::InvalidValue("sgb command length"));
+        }
+        self.command = vec![0; command_len];
+        reader.bytes(&mut self.command)?;
+        for color in self.palettes.iter_mut().flatten() {
+            *color = reader.u16()? & 0x7FFF;
+        }
+        reader.bytes(&mut self.attributes)?;
+        for attribute in self.attributes.iter_mut() {
+            *attribute &= 0x03;
+        }
+        self.mask = reader.u8()?.into();
+        self.players = match reader.u8()? {
+            players @ (1 | 2 | 4) => players,
+            _ => return Err(StateError::InvalidValue("sgb player count")),
+        };
+        self.player = reader.u8()? % self.players;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send_packet(sgb: &mut Sgb, packet: &[u8; PACKET_SIZE]) {
+        sgb.write_joypad(0x00);
+        sgb.write_joypad(0x30);
+        for i in 0..PACKET_SIZE * 8 {
+            let bit = (packet[i / 8] >> (i % 8)) & 1;
+            sgb.write_joypad(if bit == 1 { 0x10 } else { 0x20 });
+            sgb.write_joypad(0x30);
+        }
+        sgb.write_joypad(0x20);
+        sgb.write_joypad(0x30);
+    }
+
+    #[test]
+    fn test_palettes_and_attributes() {
+        let mut sgb = Sgb::default();
+        let mut packet = [0; PACKET_SIZE];
+        packet[0] = (PAL23 << 3) | 1;
+        packet[1..3].copy_from_slice(&0x001Fu16.to_le_bytes()); // shared color 0
+        packet[7..9].copy_from_slice(&0x03E0u16.to_le_bytes()); // palette 2, color 3
+        packet[13..15].copy_from_slice(&0x7C00u16.to_le_bytes()); // palette 3, color 3
+        send_packet(&mut sgb, &packet);
+        assert_eq!(sgb.palettes[0][0], 0x001F);
+        assert_eq!(sgb.palettes[2][3], 0x03E0);
+        assert_eq!(sgb.palettes[3][3], 0x7C00);
+
+        // only inside set, cells 1 - 2 across and down use palette 2, border included
+        let mut packet = [0; PACKET_SIZE];
+        packet[0] = (ATTR_BLK << 3) | 1;
+        packet[1..8].copy_from_slice(&[1, 0x01, 0x02, 1, 1, 2, 2]);
+        send_packet(&mut sgb, &packet);
+        assert_eq!(sgb.attributes[ATTR_WIDTH + 1], 2);
+        assert_eq!(sgb.attributes[2 * ATTR_WIDTH + 2], 2);
+        assert_eq!(sgb.attributes[0], 0);
+        assert_eq!(sgb.color(8, 8, GameboyColor::Black), 0xFF00FF00);
+
+        // horizontal line on row 17 with palette 3
+        let mut packet = [0; PACKET_SIZE];
+        packet[0] = (ATTR_LIN << 3) | 1;
+        packet[1..3].copy_from_slice(&[1, 0x80 | (3 << 5) | 17]);
+        send_packet(&mut sgb, &packet);
+        assert_eq!(sgb.color(159, 143, GameboyColor::Black), 0xFF0000FF);
+
+        let mut packet = [0; PACKET_SIZE];
+        packet[0] = (MASK_EN << 3) | 1;
+        packet[1] = 2;
+        send_packet(&mut sgb, &packet);
+        assert_eq!(sgb.mask, Mask::Black);
+    }
+
+    #[test]
+    fn test_reset_pulse() {
+        let mut sgb = Sgb::default();
+        sgb.write_joypad(0x20);
+        sgb.write_joypad(0x00);
+        assert!(!sgb.receiving);
+        sgb.write_joypad(0x30);
+        sgb.write_joypad(0x00);
+        assert!(sgb.receiving);
+    }
+
+    #[test]
+    fn test_multiplayer() {
+        let mut sgb = Sgb {
+            enabled: true,
+            ..Sgb::default()
+        };
+        assert_eq!(sgb.joypad_id(), None);
+        let mut packet = [0; PACKET_SIZE];
+        packet[0] = (MLT_REQ << 3) | 1;
+        packet[1] = 1;
+        send_packet(&mut sgb, &packet);
+        let id = sgb.joypad_id();
+        sgb.write_joypad(0x10);
+        sgb.write_joypad(0x30);
+        assert_ne!(sgb.joypad_id(), id);
+        sgb.write_joypad(0x10);
+        sgb.write_joypad(0x30);
+        assert_eq!(sgb.joypad_id(), id);
+    }
+}
@@ -0,0 +1,82 @@
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const HEADER_SIZE: u32 = 44;
+
+// 16 bit PCM writer, the size fields are patched in when the recording is finished
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    data_size: u32,
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        WavWriter::new(BufWriter::new(File::create(path)?), sample_rate, channels)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        let block_align = channels * 2;
+        out.write_all(b"RIFF")?;
+        out.write_u32::<LittleEndian>(HEADER_SIZE - 8)?;
+        out.write_all(b"WAVEfmt ")?;
+        out.write_u32::<LittleEndian>(16)?;
+        out.write_u16::<LittleEndian>(1)?; // PCM
+        out.write_u16::<LittleEndian>(channels)?;
+        out.write_u32::<LittleEndian>(sample_rate)?;
+        out.write_u32::<LittleEndian>(sample_rate * block_align as u32)?;
+        out.write_u16::<LittleEndian>(block_align)?;
+        out.write_u16::<LittleEndian>(16)?;
+        out.write_all(b"data")?;
+        out.write_u32::<LittleEndian>(0)?;
+        Ok(WavWriter {
+            out,
+            data_size: 0,
+        })
+    }
+
+    // Interleaved samples in -1.0 - 1.0
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.out.write_i16::<LittleEndian>(value)?;
+        }
+        self.data_size += samples.len() as u32 * 2;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_u32::<LittleEndian>(HEADER_SIZE - 8 + self.data_size)?;
+        self.out.seek(SeekFrom::Start(HEADER_SIZE as u64 - 4))?;
+        self.out.write_u32::<LittleEndian>(self.data_size)?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{ByteOrder, LittleEndian};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_header_and_samples() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 65536, 2).unwrap();
+        wav.write_samples(&[0.0, 1.0, -1.0, 2.0]).unwrap();
+        let data = wav.finish().unwrap().into_inner();
+        assert_eq!(data.len(), 44 + 8);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(LittleEndian::read_u32(&data[4..8]), 36 + 8);
+        assert_eq!(LittleEndian::read_u32(&data[24..28]), 65536);
+        assert_eq!(LittleEndian::read_u32(&data[40..44]), 8);
+        assert_eq!(LittleEndian::read_i16(&data[46..48]), i16::MAX);
+        assert_eq!(LittleEndian::read_i16(&data[48..50]), -i16::MAX);
+        assert_eq!(LittleEndian::read_i16(&data[50..52]), i16::MAX);
+    }
+}